@@ -17,12 +17,30 @@ module.exports = function(homebridge) {
     return newSwitch;
   }
 
+  createSleepTimer = function(name) {
+    let sleepTimer = new Service.Lightbulb(name, "sleep-timer");
+    // brightness is the number of minutes remaining
+    sleepTimer.addCharacteristic(Characteristic.Brightness);
+    return sleepTimer;
+  }
+
   createSpeaker = function(name) {
     let newSpeaker = new Service.Speaker(name);
     newSpeaker.addCharacteristic(Characteristic.Volume);
     return newSpeaker;
   }
 
+  createSwitch = function(name, subtype) {
+    return new Service.Switch(name, subtype);
+  }
+
+  removeServices = function(accessory) {
+    // the accessory information is managed by Homebridge
+    accessory.services
+      .filter(service => !(service instanceof Service.AccessoryInformation))
+      .forEach(service => accessory.removeService(service));
+  }
+
   constructor = partial(SpotifyPlatform, homebridge);
   homebridge.registerPlatform("homebridge-rusty-spotify", "Spotify", constructor, true);
 }
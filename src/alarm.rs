@@ -0,0 +1,286 @@
+//! Wake-up alarm routine starting a playlist and raising the volume gradually.
+
+use js_sys::{Date, Function};
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::{spawn_local, JsFuture};
+
+use crate::config::{parse_time, AlarmConfig};
+use crate::logger;
+use crate::node_fetch::error_message;
+use crate::poll_scheduler::PollScheduler;
+use crate::spotify_accessory::{create_switch, respond, Accessory, SpotifyAccessory, UUIDGen};
+use crate::spotify_api::SpotifyApi;
+use crate::spotify_platform::Service;
+use crate::timer::Timeout;
+use crate::volume_ramp::VolumeRamp;
+
+/// Volume playback gets started with if not configured.
+const DEFAULT_START_VOLUME: u32 = 5;
+/// Volume reached at the end of the ramp if not configured.
+const DEFAULT_TARGET_VOLUME: u32 = 50;
+/// Duration of the ramp if not configured.
+const DEFAULT_RAMP_DURATION: u32 = 5 * 60; // seconds
+
+#[derive(Debug)]
+/// Runs the alarm, shared with the switch handlers and the daily timeout.
+struct Routine {
+    /// Alarm configuration
+    config: AlarmConfig,
+    /// Switch service, On while the alarm is running
+    service: Service,
+    /// API to control Spotify
+    api: Rc<SpotifyApi>,
+    /// Registered Spotify devices, whose volume ramp the alarm uses
+    devices: Rc<RefCell<Vec<SpotifyAccessory>>>,
+    /// Refresh scheduler, resumed when the alarm goes off
+    scheduler: Rc<PollScheduler>,
+    /// Whether the alarm is running
+    running: Cell<bool>,
+    /// Volume ramp of the device while the alarm is raising the volume
+    ramp: RefCell<Option<Rc<VolumeRamp>>>,
+    /// Timeout starting the alarm at the configured time
+    timeout: RefCell<Option<Timeout>>,
+}
+
+#[derive(Debug)]
+/// Represents a wake-up alarm and its switch accessory.
+pub struct Alarm {
+    /// Alarm state and behaviour
+    routine: Rc<Routine>,
+    /// Accessory to be registered to Homebridge
+    accessory: Accessory,
+    /// Characteristic handlers, released when the alarm is dropped
+    handlers: Vec<Box<dyn Any>>,
+}
+
+impl Alarm {
+    pub fn new(
+        config: AlarmConfig,
+        api: Rc<SpotifyApi>,
+        devices: Rc<RefCell<Vec<SpotifyAccessory>>>,
+        scheduler: Rc<PollScheduler>,
+    ) -> Alarm {
+        let accessory = Accessory::new(
+            &config.name,
+            &UUIDGen::generate(&format!("alarm:{}", config.name)),
+        );
+        let service = create_switch(&config.name);
+        accessory.add_service(&service);
+
+        let mut alarm = Alarm {
+            routine: Rc::new(Routine {
+                config,
+                service,
+                api,
+                devices,
+                scheduler,
+                running: Cell::new(false),
+                ramp: RefCell::new(None),
+                timeout: RefCell::new(None),
+            }),
+            accessory,
+            handlers: Vec::new(),
+        };
+
+        alarm.apply_characteristics();
+        alarm.routine.schedule();
+        alarm
+    }
+
+    /// Return the alarm name.
+    pub fn get_name(&self) -> &str {
+        &self.routine.config.name
+    }
+
+    /// Return the Homebridge accessory.
+    pub fn get_accessory(&self) -> &Accessory {
+        &self.accessory
+    }
+
+    /// Stop waiting for the configured time, e.g. when Homebridge shuts down.
+    pub fn stop(&self) {
+        self.routine.timeout.replace(None);
+        self.routine.abort();
+    }
+
+    /// Setup up Homebridge characteristics.
+    fn apply_characteristics(&mut self) {
+        let routine = Rc::clone(&self.routine);
+        let get_on = Closure::wrap(Box::new(move |callback: Function| {
+            respond(&callback, Ok(JsValue::from(routine.running.get())));
+        }) as Box<dyn FnMut(Function)>);
+
+        let routine = Rc::clone(&self.routine);
+        let set_on = Closure::wrap(Box::new(move |on: bool, callback: Function| {
+            if on {
+                spawn_local(Rc::clone(&routine).run());
+            } else {
+                routine.abort();
+            }
+            respond(&callback, Ok(JsValue::from(on)));
+        }) as Box<dyn FnMut(bool, Function)>);
+
+        self.routine
+            .service
+            .get_characteristic("On")
+            .on("set", set_on.as_ref().unchecked_ref())
+            .on("get", get_on.as_ref().unchecked_ref());
+
+        self.handlers.push(Box::new(get_on));
+        self.handlers.push(Box::new(set_on));
+    }
+}
+
+impl Routine {
+    /// Start the alarm at the next occurrence of the configured time.
+    fn schedule(self: &Rc<Self>) {
+        let (hours, minutes) = match self.config.time.as_deref().and_then(parse_time) {
+            Some(time) => time,
+            None => return,
+        };
+
+        let now = Date::new_0();
+        let next = Date::new_0();
+        next.set_hours(hours);
+        next.set_minutes(minutes);
+        next.set_seconds(0);
+        next.set_milliseconds(0);
+
+        if next.get_time() <= now.get_time() {
+            next.set_date(next.get_date() + 1);
+        }
+
+        let routine = Rc::downgrade(self);
+        let timeout = Timeout::new((next.get_time() - now.get_time()) as u32, move || {
+            if let Some(routine) = routine.upgrade() {
+                // rescheduling replaces this timeout, so wait until its callback returned
+                spawn_local(async move {
+                    routine.schedule();
+                    routine.run().await;
+                });
+            }
+        });
+        self.timeout.replace(Some(timeout));
+    }
+
+    /// Transfer playback to the device, start the context and raise the volume.
+    async fn run(self: Rc<Self>) {
+        if self.running.replace(true) {
+            return;
+        }
+        self.scheduler.notify_command();
+        self.push();
+        logger::info(&format!("Alarm {} started", self.config.name));
+
+        let device_id = match self.find_device().await {
+            Some(device_id) => device_id,
+            None => {
+                logger::warn(&format!(
+                    "Alarm {}: Spotify device {} is not available",
+                    self.config.name, self.config.device
+                ));
+                self.finish();
+                return;
+            }
+        };
+
+        // share the ramp with the device so that turning the device off aborts the alarm
+        let (ramp, mapping) = self
+            .devices
+            .borrow()
+            .iter()
+            .find(|d| d.get_device_id() == device_id)
+            .map(|d| (d.get_ramp(), Some(d.get_volume_mapping())))
+            .unwrap_or_default();
+        ramp.cancel();
+        self.ramp.replace(Some(Rc::clone(&ramp)));
+
+        // the alarm respects the volume cap and limits of the device
+        let limit = |volume: u32| mapping.as_ref().map_or(volume, |m| m.limit(volume));
+        let start_volume = limit(self.config.start_volume.unwrap_or(DEFAULT_START_VOLUME));
+        let target_volume = limit(self.config.target_volume.unwrap_or(DEFAULT_TARGET_VOLUME));
+        let duration = self.config.ramp_duration.unwrap_or(DEFAULT_RAMP_DURATION);
+
+        let api = &self.api;
+        let started = async {
+            JsFuture::from(api.transfer_playback(device_id.clone(), false)).await?;
+            JsFuture::from(api.set_volume(device_id.clone(), start_volume)).await?;
+            if let Some(shuffle) = self.config.shuffle {
+                JsFuture::from(api.set_shuffle(device_id.clone(), shuffle)).await?;
+            }
+            JsFuture::from(api.play_context(device_id.clone(), self.config.context_uri.clone()))
+                .await
+        };
+
+        if let Err(e) = started.await {
+            logger::error(&format!(
+                "Alarm {} failed to start playback: {}",
+                self.config.name,
+                error_message(&e)
+            ));
+            self.finish();
+            return;
+        }
+
+        // the switch might have been turned off while starting playback
+        if self.ramp.borrow().is_none() {
+            self.finish();
+            return;
+        }
+
+        let result = ramp
+            .run(
+                &self.api,
+                &device_id,
+                start_volume,
+                target_volume,
+                duration * 1000,
+            )
+            .await;
+
+        match result {
+            Ok(true) => logger::info(&format!("Alarm {} finished", self.config.name)),
+            Ok(false) => logger::info(&format!("Alarm {} aborted", self.config.name)),
+            Err(e) => logger::error(&format!(
+                "Alarm {} failed to raise the volume: {}",
+                self.config.name,
+                error_message(&e)
+            )),
+        }
+        self.finish();
+    }
+
+    /// Look up the configured device by ID or name.
+    async fn find_device(&self) -> Option<String> {
+        self.api
+            .find_device(&self.config.device)
+            .await
+            .ok()
+            .flatten()
+            .map(|d| d.id)
+    }
+
+    /// Stop raising the volume, playback continues.
+    fn abort(&self) {
+        if let Some(ramp) = self.ramp.replace(None) {
+            ramp.cancel();
+        }
+    }
+
+    /// Mark the alarm as no longer running.
+    fn finish(&self) {
+        self.ramp.replace(None);
+        self.running.set(false);
+        self.push();
+    }
+
+    /// Push whether the alarm is running to HomeKit.
+    fn push(&self) {
+        self.service
+            .update_characteristic("On", &JsValue::from(self.running.get()));
+    }
+}
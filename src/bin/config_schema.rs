@@ -0,0 +1,10 @@
+//! Print the config.schema.json used by Homebridge Config UI X.
+
+use homebridge_rusty_spotify::config::config_schema;
+
+fn main() {
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&config_schema()).expect("Error serializing config schema.")
+    );
+}
@@ -0,0 +1,1315 @@
+//! Platform configuration, its validation and the Homebridge Config UI X schema.
+
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::fmt;
+use wasm_bindgen::prelude::*;
+
+use crate::logger;
+use crate::volume_curve::VolumeCurve;
+
+/// Name of the platform as registered to Homebridge.
+pub const PLATFORM_NAME: &str = "Spotify";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+/// Service used for accessories. HomeKit doesn't support Speaker, instead use Light.
+pub enum ServiceType {
+    #[serde(rename = "light")]
+    Light,
+    #[serde(rename = "speaker")]
+    Speaker,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+/// Represents the platform configuration retrieved from ~/.homebridge/config.json
+pub struct Config {
+    /// Spotify API client_id
+    pub client_id: String,
+    /// Spotify API client_secret
+    pub client_secret: String,
+    /// Cached refresh token for Spotify API
+    pub refresh_token: String,
+    /// Device refresh rate while devices are available but nothing is playing
+    pub refresh_rate: Option<u32>,
+    /// Device refresh rate while playing or after changes from HomeKit
+    pub fast_refresh_rate: Option<u32>,
+    /// Device refresh rate while no device is active, refreshes are paused if not set
+    pub idle_refresh_rate: Option<u32>,
+    /// Service type for new accessories
+    pub service_type: Option<ServiceType>,
+    /// Per-device overrides keyed by Spotify device name or ID
+    pub devices: Option<HashMap<String, DeviceConfig>>,
+    /// Log requests and responses to the Spotify API
+    pub debug: Option<bool>,
+    /// Wake-up alarms starting playback on a device
+    pub alarms: Option<Vec<AlarmConfig>>,
+    /// Switch temporarily lowering the volume
+    pub ducking: Option<DuckingConfig>,
+    /// Switches capturing and restoring the playback state
+    pub snapshots: Option<Vec<SnapshotConfig>>,
+    /// Switches playing or queueing specific content
+    pub presets: Option<Vec<PresetConfig>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+/// Represents overrides for a single Spotify device.
+pub struct DeviceConfig {
+    /// Name displayed in HomeKit instead of the Spotify device name
+    pub name: Option<String>,
+    /// Service type overriding the platform-wide service type
+    pub service_type: Option<ServiceType>,
+    /// Lowest volume that can be set on the device
+    pub min_volume: Option<u32>,
+    /// Highest volume that can be set on the device
+    pub max_volume: Option<u32>,
+    /// Volume applied when playback gets started
+    pub default_volume: Option<u32>,
+    /// Whether playback can be started and paused from HomeKit
+    pub allow_play_pause: Option<bool>,
+    /// Curve mapping HomeKit slider values to Spotify volumes
+    pub volume_curve: Option<VolumeCurve>,
+    /// Spotify volume at the top of the HomeKit slider
+    pub volume_cap: Option<u32>,
+    /// Seconds over which the volume is raised when playback gets started
+    pub fade_in: Option<u32>,
+    /// Seconds over which the volume is lowered before playback gets paused
+    pub fade_out: Option<u32>,
+    /// Minutes the sleep timer runs by default, adds a sleep timer to the device
+    pub sleep_timer: Option<u32>,
+    /// Minutes over which the volume is lowered before the sleep timer pauses playback
+    pub sleep_timer_fade: Option<u32>,
+    /// Spotify URI played if there is nothing to resume and nothing was played before
+    pub default_context_uri: Option<String>,
+    /// Adds switches skipping back and forward
+    pub skip_switches: Option<bool>,
+    /// Seconds skipped back within podcast episodes
+    pub skip_back_seconds: Option<u32>,
+    /// Seconds skipped forward within podcast episodes
+    pub skip_forward_seconds: Option<u32>,
+    /// Adds a switch saving the current track to the library
+    pub like_switch: Option<LikeSwitchMode>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+/// Behaviour of the switch saving the current track to the library.
+pub enum LikeSwitchMode {
+    /// Saves the current track and turns itself off again
+    #[serde(rename = "momentary")]
+    Momentary,
+    /// Shows whether the current track is saved, turning it off removes the track
+    #[serde(rename = "stateful")]
+    Stateful,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+/// Represents a wake-up alarm routine.
+pub struct AlarmConfig {
+    /// Name of the switch starting the alarm
+    pub name: String,
+    /// Spotify device name or ID to play on
+    pub device: String,
+    /// Spotify URI of the album, artist or playlist to play
+    pub context_uri: String,
+    /// Time of day at which the alarm goes off, `HH:MM` in local time
+    pub time: Option<String>,
+    /// Whether playback is shuffled
+    pub shuffle: Option<bool>,
+    /// Volume playback gets started with
+    pub start_volume: Option<u32>,
+    /// Volume reached at the end of the ramp
+    pub target_volume: Option<u32>,
+    /// Seconds over which the volume is raised
+    pub ramp_duration: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+/// Represents the switch ducking playback.
+pub struct DuckingConfig {
+    /// Name of the switch
+    pub name: String,
+    /// Volume while ducked, playback gets paused if not set
+    pub volume: Option<u32>,
+    /// Seconds after which the volume is restored
+    pub duration: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+/// Represents a switch capturing the playback state when turned on and restoring it when turned off.
+pub struct SnapshotConfig {
+    /// Name of the switch and the snapshot
+    pub name: String,
+    /// Whether playback is paused after capturing it
+    pub pause: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+/// Action performed when a preset switch is turned on.
+pub enum PresetAction {
+    #[serde(rename = "play")]
+    Play,
+    #[serde(rename = "queue")]
+    Queue,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+/// Represents a switch playing or queueing specific content.
+pub struct PresetConfig {
+    /// Name of the switch
+    pub name: String,
+    /// Spotify URI of the album, artist, playlist, track or episode
+    pub uri: Option<String>,
+    /// Name of one of the user's playlists, resolved at startup
+    pub playlist: Option<String>,
+    /// Name of an artist, resolved at startup
+    pub artist: Option<String>,
+    /// Search query, resolved to the first result at startup
+    pub search: Option<String>,
+    /// Type of item searched for, `track` if not set
+    pub search_type: Option<String>,
+    /// Whether the content is played right away or added to the queue
+    pub action: Option<PresetAction>,
+    /// Spotify device name or ID, the active device if not set
+    pub device: Option<String>,
+}
+
+/// Describes a configuration field, used for validation and for generating config.schema.json.
+pub struct Field {
+    /// Key in the configuration
+    pub name: &'static str,
+    /// Title shown in Homebridge Config UI X
+    pub title: &'static str,
+    /// Description shown in Homebridge Config UI X
+    pub description: &'static str,
+    /// Expected type of the value
+    pub kind: FieldKind,
+    /// Whether the field needs to be set
+    pub required: bool,
+}
+
+/// Expected type of a configuration value.
+pub enum FieldKind {
+    String,
+    Boolean,
+    Integer {
+        min: i64,
+        max: Option<i64>,
+    },
+    /// One of the listed strings
+    Choice(&'static [&'static str]),
+    /// Object with arbitrary keys mapping to objects with the listed fields
+    Map(&'static [Field]),
+    /// List of objects with the listed fields
+    List(&'static [Field]),
+    /// Object with the listed fields
+    Object(&'static [Field]),
+    /// Time of day formatted as `HH:MM`
+    Time,
+    /// List of `[a, b]` integer pairs
+    IntegerPairs {
+        min: i64,
+        max: i64,
+    },
+    /// Value matching any of the listed kinds
+    OneOf(&'static [FieldKind]),
+}
+
+/// Fields available for each entry in `devices`.
+const DEVICE_FIELDS: &[Field] = &[
+    Field {
+        name: "name",
+        title: "Name",
+        description: "Name displayed in HomeKit instead of the Spotify device name.",
+        kind: FieldKind::String,
+        required: false,
+    },
+    Field {
+        name: "service_type",
+        title: "Service Type",
+        description: "Overrides the platform-wide service type for this device.",
+        kind: FieldKind::Choice(&["light", "speaker"]),
+        required: false,
+    },
+    Field {
+        name: "min_volume",
+        title: "Minimum Volume",
+        description: "Lowest volume that can be set on the device.",
+        kind: FieldKind::Integer {
+            min: 0,
+            max: Some(100),
+        },
+        required: false,
+    },
+    Field {
+        name: "max_volume",
+        title: "Maximum Volume",
+        description: "Highest volume that can be set on the device.",
+        kind: FieldKind::Integer {
+            min: 0,
+            max: Some(100),
+        },
+        required: false,
+    },
+    Field {
+        name: "default_volume",
+        title: "Default Volume",
+        description: "Volume applied when playback is started from HomeKit.",
+        kind: FieldKind::Integer {
+            min: 0,
+            max: Some(100),
+        },
+        required: false,
+    },
+    Field {
+        name: "allow_play_pause",
+        title: "Allow Play/Pause",
+        description: "Whether playback can be started and paused from HomeKit.",
+        kind: FieldKind::Boolean,
+        required: false,
+    },
+    Field {
+        name: "volume_curve",
+        title: "Volume Curve",
+        description: "Mapping of the HomeKit slider to the Spotify volume: \"linear\", \"logarithmic\" or a list of [slider, volume] breakpoints.",
+        kind: FieldKind::OneOf(&[
+            FieldKind::Choice(&["linear", "logarithmic"]),
+            FieldKind::IntegerPairs { min: 0, max: 100 },
+        ]),
+        required: false,
+    },
+    Field {
+        name: "volume_cap",
+        title: "Volume Cap",
+        description: "Spotify volume at the top of the HomeKit slider, the volume curve is scaled to it.",
+        kind: FieldKind::Integer {
+            min: 1,
+            max: Some(100),
+        },
+        required: false,
+    },
+    Field {
+        name: "fade_in",
+        title: "Fade In",
+        description: "Seconds over which the volume is raised when playback is started from HomeKit.",
+        kind: FieldKind::Integer {
+            min: 0,
+            max: Some(600),
+        },
+        required: false,
+    },
+    Field {
+        name: "fade_out",
+        title: "Fade Out",
+        description: "Seconds over which the volume is lowered before playback is paused from HomeKit.",
+        kind: FieldKind::Integer {
+            min: 0,
+            max: Some(600),
+        },
+        required: false,
+    },
+    Field {
+        name: "sleep_timer",
+        title: "Sleep Timer",
+        description: "Adds a sleep timer to the device which runs for the given number of minutes by default.",
+        kind: FieldKind::Integer {
+            min: 1,
+            max: Some(100),
+        },
+        required: false,
+    },
+    Field {
+        name: "sleep_timer_fade",
+        title: "Sleep Timer Fade",
+        description: "Minutes over which the volume is lowered before the sleep timer pauses playback.",
+        kind: FieldKind::Integer {
+            min: 0,
+            max: Some(100),
+        },
+        required: false,
+    },
+    Field {
+        name: "default_context_uri",
+        title: "Default Playlist",
+        description: "Spotify URI of the album, artist or playlist started when there is nothing to resume and nothing was played on the device before.",
+        kind: FieldKind::String,
+        required: false,
+    },
+    Field {
+        name: "skip_switches",
+        title: "Skip Switches",
+        description: "Adds switches skipping to the previous and next track, or back and forward within podcast episodes.",
+        kind: FieldKind::Boolean,
+        required: false,
+    },
+    Field {
+        name: "skip_back_seconds",
+        title: "Skip Back Seconds",
+        description: "Seconds skipped back within podcast episodes. Defaults to 15.",
+        kind: FieldKind::Integer {
+            min: 1,
+            max: Some(600),
+        },
+        required: false,
+    },
+    Field {
+        name: "skip_forward_seconds",
+        title: "Skip Forward Seconds",
+        description: "Seconds skipped forward within podcast episodes. Defaults to 30.",
+        kind: FieldKind::Integer {
+            min: 1,
+            max: Some(600),
+        },
+        required: false,
+    },
+    Field {
+        name: "like_switch",
+        title: "Like Switch",
+        description: "Adds a switch saving the track or episode playing on the device to the library. \"momentary\" turns itself off again, \"stateful\" shows whether the current track is saved and removes it when turned off.",
+        kind: FieldKind::Choice(&["momentary", "stateful"]),
+        required: false,
+    },
+];
+
+/// Fields available for each entry in `alarms`.
+const ALARM_FIELDS: &[Field] = &[
+    Field {
+        name: "name",
+        title: "Name",
+        description: "Name of the switch starting the alarm.",
+        kind: FieldKind::String,
+        required: true,
+    },
+    Field {
+        name: "device",
+        title: "Device",
+        description: "Spotify device name or ID to play on.",
+        kind: FieldKind::String,
+        required: true,
+    },
+    Field {
+        name: "context_uri",
+        title: "Context URI",
+        description: "Spotify URI of the album, artist or playlist to play, e.g. spotify:playlist:37i9dQZF1DXcBWIGoYBM5M.",
+        kind: FieldKind::String,
+        required: true,
+    },
+    Field {
+        name: "time",
+        title: "Time",
+        description: "Time of day at which the alarm goes off every day (HH:MM). Without a time, the alarm only starts from its switch.",
+        kind: FieldKind::Time,
+        required: false,
+    },
+    Field {
+        name: "shuffle",
+        title: "Shuffle",
+        description: "Whether playback is shuffled.",
+        kind: FieldKind::Boolean,
+        required: false,
+    },
+    Field {
+        name: "start_volume",
+        title: "Start Volume",
+        description: "Volume playback is started with.",
+        kind: FieldKind::Integer {
+            min: 0,
+            max: Some(100),
+        },
+        required: false,
+    },
+    Field {
+        name: "target_volume",
+        title: "Target Volume",
+        description: "Volume reached at the end of the ramp.",
+        kind: FieldKind::Integer {
+            min: 0,
+            max: Some(100),
+        },
+        required: false,
+    },
+    Field {
+        name: "ramp_duration",
+        title: "Ramp Duration",
+        description: "Seconds over which the volume is raised from the start to the target volume.",
+        kind: FieldKind::Integer {
+            min: 0,
+            max: Some(3600),
+        },
+        required: false,
+    },
+];
+
+/// Fields available for `ducking`.
+const DUCKING_FIELDS: &[Field] = &[
+    Field {
+        name: "name",
+        title: "Name",
+        description: "Name of the switch ducking playback.",
+        kind: FieldKind::String,
+        required: true,
+    },
+    Field {
+        name: "volume",
+        title: "Volume",
+        description: "Volume while ducked. If not set, playback is paused instead.",
+        kind: FieldKind::Integer {
+            min: 0,
+            max: Some(100),
+        },
+        required: false,
+    },
+    Field {
+        name: "duration",
+        title: "Duration",
+        description: "Seconds after which the previous volume is restored.",
+        kind: FieldKind::Integer {
+            min: 1,
+            max: Some(3600),
+        },
+        required: false,
+    },
+];
+
+/// Fields available for each entry in `snapshots`.
+const SNAPSHOT_FIELDS: &[Field] = &[
+    Field {
+        name: "name",
+        title: "Name",
+        description: "Name of the switch capturing the playback state.",
+        kind: FieldKind::String,
+        required: true,
+    },
+    Field {
+        name: "pause",
+        title: "Pause",
+        description: "Whether playback is paused after capturing it. Defaults to true.",
+        kind: FieldKind::Boolean,
+        required: false,
+    },
+];
+
+/// Fields available for each entry in `presets`.
+const PRESET_FIELDS: &[Field] = &[
+    Field {
+        name: "name",
+        title: "Name",
+        description: "Name of the switch.",
+        kind: FieldKind::String,
+        required: true,
+    },
+    Field {
+        name: "uri",
+        title: "URI",
+        description: "Spotify URI of the album, artist, playlist, track or episode, e.g. spotify:track:4iV5W9uYEdYUVa79Axb7Rh. Set either a URI, a playlist, an artist or a search query.",
+        kind: FieldKind::String,
+        required: false,
+    },
+    Field {
+        name: "playlist",
+        title: "Playlist",
+        description: "Name of one of your playlists, looked up when Homebridge starts.",
+        kind: FieldKind::String,
+        required: false,
+    },
+    Field {
+        name: "artist",
+        title: "Artist",
+        description: "Name of an artist, looked up when Homebridge starts.",
+        kind: FieldKind::String,
+        required: false,
+    },
+    Field {
+        name: "search",
+        title: "Search",
+        description: "Search query, the first result is looked up when Homebridge starts.",
+        kind: FieldKind::String,
+        required: false,
+    },
+    Field {
+        name: "search_type",
+        title: "Search Type",
+        description: "Type of item searched for. Defaults to track.",
+        kind: FieldKind::Choice(&["track", "album", "artist", "playlist", "show"]),
+        required: false,
+    },
+    Field {
+        name: "action",
+        title: "Action",
+        description: "Whether the content is played right away or added to the queue. Only tracks and episodes can be queued. Defaults to play.",
+        kind: FieldKind::Choice(&["play", "queue"]),
+        required: false,
+    },
+    Field {
+        name: "device",
+        title: "Device",
+        description: "Spotify device name or ID. If not set, the device currently playing is used.",
+        kind: FieldKind::String,
+        required: false,
+    },
+];
+
+/// Fields available in the platform configuration.
+pub const CONFIG_FIELDS: &[Field] = &[
+    Field {
+        name: "name",
+        title: "Name",
+        description: "Name of the platform.",
+        kind: FieldKind::String,
+        required: false,
+    },
+    Field {
+        name: "client_id",
+        title: "Client ID",
+        description: "Client ID of the app registered in the Spotify Developer Dashboard.",
+        kind: FieldKind::String,
+        required: true,
+    },
+    Field {
+        name: "client_secret",
+        title: "Client Secret",
+        description: "Client secret of the app registered in the Spotify Developer Dashboard.",
+        kind: FieldKind::String,
+        required: true,
+    },
+    Field {
+        name: "refresh_token",
+        title: "Refresh Token",
+        description: "Refresh token retrieved by running the generate_config script.",
+        kind: FieldKind::String,
+        required: true,
+    },
+    Field {
+        name: "service_type",
+        title: "Service Type",
+        description: "Service used for Spotify devices. Speaker is not supported by the Home app.",
+        kind: FieldKind::Choice(&["light", "speaker"]),
+        required: false,
+    },
+    Field {
+        name: "refresh_rate",
+        title: "Refresh Rate",
+        description:
+            "Milliseconds between refreshes while a device is active but nothing is playing.",
+        kind: FieldKind::Integer {
+            min: 1000,
+            max: None,
+        },
+        required: false,
+    },
+    Field {
+        name: "fast_refresh_rate",
+        title: "Fast Refresh Rate",
+        description: "Milliseconds between refreshes while music is playing.",
+        kind: FieldKind::Integer {
+            min: 1000,
+            max: None,
+        },
+        required: false,
+    },
+    Field {
+        name: "idle_refresh_rate",
+        title: "Idle Refresh Rate",
+        description: "Milliseconds between refreshes while no device is active. Refreshes are paused until the next change from HomeKit if not set.",
+        kind: FieldKind::Integer {
+            min: 1000,
+            max: None,
+        },
+        required: false,
+    },
+    Field {
+        name: "devices",
+        title: "Devices",
+        description: "Overrides for individual devices, keyed by Spotify device name or ID.",
+        kind: FieldKind::Map(DEVICE_FIELDS),
+        required: false,
+    },
+    Field {
+        name: "alarms",
+        title: "Alarms",
+        description:
+            "Wake-up alarms starting a playlist on a device and raising the volume gradually.",
+        kind: FieldKind::List(ALARM_FIELDS),
+        required: false,
+    },
+    Field {
+        name: "ducking",
+        title: "Ducking",
+        description: "Adds a switch lowering the volume temporarily, e.g. when the doorbell rings.",
+        kind: FieldKind::Object(DUCKING_FIELDS),
+        required: false,
+    },
+    Field {
+        name: "snapshots",
+        title: "Snapshots",
+        description:
+            "Switches capturing the playback state when turned on and restoring it when turned off.",
+        kind: FieldKind::List(SNAPSHOT_FIELDS),
+        required: false,
+    },
+    Field {
+        name: "presets",
+        title: "Presets",
+        description:
+            "Switches playing an album, playlist or track, or adding a track to the queue.",
+        kind: FieldKind::List(PRESET_FIELDS),
+        required: false,
+    },
+    Field {
+        name: "debug",
+        title: "Debug",
+        description: "Log all requests to and responses from the Spotify API.",
+        kind: FieldKind::Boolean,
+        required: false,
+    },
+];
+
+#[derive(Debug)]
+/// Represents an invalid configuration value.
+pub struct ConfigError {
+    /// Path to the invalid field, e.g. `devices.Kitchen.max_volume`
+    pub field: String,
+    /// Description of the problem
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "`{}` {}", self.field, self.message)
+    }
+}
+
+impl Config {
+    /// Validate and parse the configuration passed by Homebridge.
+    pub fn from_js(config: &JsValue) -> Result<Config, Vec<ConfigError>> {
+        #[allow(deprecated)]
+        let value: Value = config.into_serde().map_err(|e| {
+            vec![ConfigError {
+                field: "config".to_owned(),
+                message: format!("is not valid JSON: {}", e),
+            }]
+        })?;
+
+        Self::from_value(value)
+    }
+
+    /// Validate and parse the configuration.
+    pub fn from_value(value: Value) -> Result<Config, Vec<ConfigError>> {
+        let mut errors = Vec::new();
+        validate_object(&value, CONFIG_FIELDS, "", &mut errors);
+
+        if let Some(devices) = value.get("devices").and_then(Value::as_object) {
+            for (device, device_config) in devices {
+                let path = format!("devices.{}", device);
+                validate_volume_limits(device_config, &path, &mut errors);
+                validate_volume_curve(device_config, &path, &mut errors);
+            }
+        }
+
+        if let Some(alarms) = value.get("alarms").and_then(Value::as_array) {
+            validate_unique_names(alarms, "alarms", &mut errors);
+        }
+
+        if let Some(snapshots) = value.get("snapshots").and_then(Value::as_array) {
+            validate_unique_names(snapshots, "snapshots", &mut errors);
+        }
+
+        if let Some(presets) = value.get("presets").and_then(Value::as_array) {
+            validate_unique_names(presets, "presets", &mut errors);
+            for (index, preset) in presets.iter().enumerate() {
+                validate_preset(
+                    preset,
+                    &join_path("presets", &index.to_string()),
+                    &mut errors,
+                );
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        serde_json::from_value(value).map_err(|e| {
+            vec![ConfigError {
+                field: "config".to_owned(),
+                message: format!("could not be loaded: {}", e),
+            }]
+        })
+    }
+
+    /// Return the overrides for a device, looked up by ID first and then by name.
+    pub fn device_config(&self, device_id: &str, device_name: &str) -> DeviceConfig {
+        self.devices
+            .as_ref()
+            .and_then(|devices| devices.get(device_id).or_else(|| devices.get(device_name)))
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+/// Check that all fields of an object have the expected types.
+fn validate_object(value: &Value, fields: &[Field], path: &str, errors: &mut Vec<ConfigError>) {
+    let object = match value.as_object() {
+        Some(object) => object,
+        None => {
+            errors.push(ConfigError {
+                field: display_path(path),
+                message: "must be an object".to_owned(),
+            });
+            return;
+        }
+    };
+
+    for field in fields {
+        let field_path = join_path(path, field.name);
+
+        match object.get(field.name) {
+            None | Some(Value::Null) if field.required => errors.push(ConfigError {
+                field: field_path,
+                message: "is missing".to_owned(),
+            }),
+            None | Some(Value::Null) => {}
+            Some(value) => validate_field(value, &field.kind, &field_path, errors),
+        }
+    }
+
+    // unknown keys are most likely typos, but don't prevent the platform from starting
+    for key in object.keys() {
+        // `platform` and keys starting with `_`, e.g. `_bridge`, are used by Homebridge
+        if key != "platform" && !key.starts_with('_') && !fields.iter().any(|f| f.name == key) {
+            logger::warn(&format!(
+                "Unknown config option `{}` is ignored",
+                join_path(path, key)
+            ));
+        }
+    }
+}
+
+/// Check that a value matches the expected type.
+fn validate_field(value: &Value, kind: &FieldKind, path: &str, errors: &mut Vec<ConfigError>) {
+    let mut error = |message: String| {
+        errors.push(ConfigError {
+            field: path.to_owned(),
+            message,
+        })
+    };
+
+    match kind {
+        FieldKind::String => match value.as_str() {
+            Some("") => error("must not be empty".to_owned()),
+            Some(_) => {}
+            None => error("must be a string".to_owned()),
+        },
+        FieldKind::Boolean => {
+            if !value.is_boolean() {
+                error("must be true or false".to_owned());
+            }
+        }
+        FieldKind::Integer { min, max } => match value.as_i64() {
+            Some(v) if v < *min => error(format!("must be at least {}", min)),
+            Some(v) if max.is_some_and(|max| v > max) => {
+                error(format!("must be at most {}", max.unwrap_or_default()))
+            }
+            Some(_) => {}
+            None => error("must be a whole number".to_owned()),
+        },
+        FieldKind::Choice(choices) => {
+            if !value.as_str().is_some_and(|v| choices.contains(&v)) {
+                error(format!("must be one of {}", quote_choices(choices)));
+            }
+        }
+        FieldKind::Map(fields) => match value.as_object() {
+            Some(entries) => {
+                for (key, entry) in entries {
+                    validate_object(entry, fields, &join_path(path, key), errors);
+                }
+            }
+            None => error("must be an object".to_owned()),
+        },
+        FieldKind::List(fields) => match value.as_array() {
+            Some(entries) => {
+                for (index, entry) in entries.iter().enumerate() {
+                    validate_object(entry, fields, &join_path(path, &index.to_string()), errors);
+                }
+            }
+            None => error("must be a list".to_owned()),
+        },
+        FieldKind::Object(fields) => validate_object(value, fields, path, errors),
+        FieldKind::Time => {
+            if value.as_str().and_then(parse_time).is_none() {
+                error("must be a time of day formatted as HH:MM".to_owned());
+            }
+        }
+        FieldKind::IntegerPairs { min, max } => {
+            let valid = value.as_array().is_some_and(|pairs| {
+                pairs.iter().all(|pair| {
+                    pair.as_array().is_some_and(|pair| {
+                        pair.len() == 2
+                            && pair
+                                .iter()
+                                .all(|v| v.as_i64().is_some_and(|v| v >= *min && v <= *max))
+                    })
+                })
+            });
+
+            if !valid {
+                error(format!(
+                    "must be a list of [a, b] pairs with values between {} and {}",
+                    min, max
+                ));
+            }
+        }
+        FieldKind::OneOf(kinds) => {
+            let mut kind_errors = Vec::new();
+
+            // valid if any of the kinds matches, otherwise report why the value doesn't match
+            for kind in kinds.iter() {
+                let mut current_errors = Vec::new();
+                validate_field(value, kind, path, &mut current_errors);
+
+                if current_errors.is_empty() {
+                    return;
+                }
+                kind_errors.extend(current_errors.into_iter().map(|e| e.message));
+            }
+
+            error(kind_errors.join(" or "));
+        }
+    }
+}
+
+/// Check that volume curve breakpoints are sorted.
+fn validate_volume_curve(device: &Value, path: &str, errors: &mut Vec<ConfigError>) {
+    let breakpoints = match device.get("volume_curve").and_then(Value::as_array) {
+        Some(breakpoints) => breakpoints,
+        None => return,
+    };
+
+    let pairs: Vec<(i64, i64)> = breakpoints
+        .iter()
+        .filter_map(|pair| Some((pair.get(0)?.as_i64()?, pair.get(1)?.as_i64()?)))
+        .collect();
+
+    if pairs.is_empty() {
+        errors.push(ConfigError {
+            field: join_path(path, "volume_curve"),
+            message: "must contain at least one breakpoint".to_owned(),
+        });
+    } else if pairs
+        .windows(2)
+        .any(|w| w[0].0 >= w[1].0 || w[0].1 > w[1].1)
+    {
+        errors.push(ConfigError {
+            field: join_path(path, "volume_curve"),
+            message: "breakpoints must be sorted by slider value and must not decrease in volume"
+                .to_owned(),
+        });
+    }
+}
+
+/// Check that a preset refers to exactly one item and that the item can be used with its action.
+fn validate_preset(preset: &Value, path: &str, errors: &mut Vec<ConfigError>) {
+    let targets: Vec<&str> = ["uri", "playlist", "artist", "search"]
+        .iter()
+        .copied()
+        .filter(|key| preset.get(key).is_some_and(|value| !value.is_null()))
+        .collect();
+
+    let target = match targets.as_slice() {
+        [target] => *target,
+        _ => {
+            errors.push(ConfigError {
+                field: display_path(path),
+                message: "must set exactly one of `uri`, `playlist`, `artist` or `search`"
+                    .to_owned(),
+            });
+            return;
+        }
+    };
+
+    let queue = preset.get("action").and_then(Value::as_str) == Some("queue");
+
+    match target {
+        "uri" => {
+            let uri = preset
+                .get("uri")
+                .and_then(Value::as_str)
+                .unwrap_or_default();
+            let kind = match uri.split(':').collect::<Vec<_>>().as_slice() {
+                ["spotify", kind, id] if !id.is_empty() => *kind,
+                _ => {
+                    errors.push(ConfigError {
+                        field: join_path(path, "uri"),
+                        message: "must be a Spotify URI like spotify:track:<id>".to_owned(),
+                    });
+                    return;
+                }
+            };
+
+            if queue && kind != "track" && kind != "episode" {
+                errors.push(ConfigError {
+                    field: join_path(path, "uri"),
+                    message: "must be a track or episode to be queued".to_owned(),
+                });
+            }
+        }
+        "search" => {
+            let kind = preset.get("search_type").and_then(Value::as_str);
+            if queue && kind.is_some_and(|kind| kind != "track") {
+                errors.push(ConfigError {
+                    field: join_path(path, "search_type"),
+                    message: "must be track to be queued".to_owned(),
+                });
+            }
+        }
+        _ => {
+            if queue {
+                errors.push(ConfigError {
+                    field: join_path(path, "action"),
+                    message: format!("must be play when using `{}`", target),
+                });
+            }
+        }
+    }
+}
+
+/// Check that no two entries share a name, since the name identifies their accessory.
+fn validate_unique_names(entries: &[Value], path: &str, errors: &mut Vec<ConfigError>) {
+    let mut names = Vec::new();
+
+    for (index, entry) in entries.iter().enumerate() {
+        if let Some(name) = entry.get("name").and_then(Value::as_str) {
+            if names.contains(&name) {
+                errors.push(ConfigError {
+                    field: join_path(&join_path(path, &index.to_string()), "name"),
+                    message: format!("\"{}\" is used more than once", name),
+                });
+            }
+            names.push(name);
+        }
+    }
+}
+
+/// Parse a time of day formatted as `HH:MM` into hours and minutes.
+pub fn parse_time(time: &str) -> Option<(u32, u32)> {
+    let (hours, minutes) = time.split_once(':')?;
+
+    let two_digits = |part: &str| part.len() == 2 && part.bytes().all(|b| b.is_ascii_digit());
+    if !two_digits(hours) || !two_digits(minutes) {
+        return None;
+    }
+
+    let hours = hours.parse().ok().filter(|&h| h < 24)?;
+    let minutes = minutes.parse().ok().filter(|&m| m < 60)?;
+    Some((hours, minutes))
+}
+
+/// Check that the volume limits of a device don't contradict each other.
+fn validate_volume_limits(device: &Value, path: &str, errors: &mut Vec<ConfigError>) {
+    let min_volume = device
+        .get("min_volume")
+        .and_then(Value::as_i64)
+        .unwrap_or(0);
+    let max_volume = device
+        .get("max_volume")
+        .and_then(Value::as_i64)
+        .unwrap_or(100);
+
+    if min_volume > max_volume {
+        errors.push(ConfigError {
+            field: join_path(path, "min_volume"),
+            message: "must not be greater than `max_volume`".to_owned(),
+        });
+    } else if let Some(default_volume) = device.get("default_volume").and_then(Value::as_i64) {
+        let volume_cap = device
+            .get("volume_cap")
+            .and_then(Value::as_i64)
+            .unwrap_or(100);
+
+        if default_volume < min_volume || default_volume > max_volume {
+            errors.push(ConfigError {
+                field: join_path(path, "default_volume"),
+                message: format!("must be between {} and {}", min_volume, max_volume),
+            });
+        } else if default_volume > volume_cap {
+            errors.push(ConfigError {
+                field: join_path(path, "default_volume"),
+                message: "must not be greater than `volume_cap`".to_owned(),
+            });
+        }
+    }
+}
+
+fn join_path(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.to_owned()
+    } else {
+        format!("{}.{}", path, key)
+    }
+}
+
+fn display_path(path: &str) -> String {
+    if path.is_empty() {
+        "config".to_owned()
+    } else {
+        path.to_owned()
+    }
+}
+
+fn quote_choices(choices: &[&str]) -> String {
+    choices
+        .iter()
+        .map(|c| format!("\"{}\"", c))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Return the config.schema.json used by Homebridge Config UI X to render the settings form.
+pub fn config_schema() -> Value {
+    let mut schema = object_schema(CONFIG_FIELDS);
+    schema["properties"]["name"]["default"] = json!(PLATFORM_NAME);
+
+    json!({
+        "pluginAlias": PLATFORM_NAME,
+        "pluginType": "platform",
+        "singular": true,
+        "schema": schema,
+    })
+}
+
+/// Return the JSON schema of an object with the provided fields.
+fn object_schema(fields: &[Field]) -> Value {
+    let mut properties = Map::new();
+
+    for field in fields {
+        let mut property = field_schema(&field.kind);
+        property["title"] = json!(field.title);
+        property["description"] = json!(field.description);
+
+        if field.required {
+            property["required"] = json!(true);
+        }
+
+        properties.insert(field.name.to_owned(), property);
+    }
+
+    json!({
+        "type": "object",
+        "properties": properties,
+    })
+}
+
+/// Return the JSON schema of a value of the provided kind.
+fn field_schema(kind: &FieldKind) -> Value {
+    match kind {
+        FieldKind::String => json!({ "type": "string" }),
+        FieldKind::Boolean => json!({ "type": "boolean" }),
+        FieldKind::Integer { min, max } => {
+            let mut schema = json!({ "type": "integer", "minimum": min });
+            if let Some(max) = max {
+                schema["maximum"] = json!(max);
+            }
+            schema
+        }
+        FieldKind::Choice(choices) => json!({ "type": "string", "enum": choices }),
+        FieldKind::Map(fields) => json!({
+            "type": "object",
+            "additionalProperties": object_schema(fields),
+        }),
+        FieldKind::List(fields) => json!({
+            "type": "array",
+            "items": object_schema(fields),
+        }),
+        FieldKind::Object(fields) => object_schema(fields),
+        FieldKind::Time => json!({
+            "type": "string",
+            "pattern": "^([01][0-9]|2[0-3]):[0-5][0-9]$",
+        }),
+        FieldKind::IntegerPairs { min, max } => json!({
+            "type": "array",
+            "items": {
+                "type": "array",
+                "items": { "type": "integer", "minimum": min, "maximum": max },
+                "minItems": 2,
+                "maxItems": 2,
+            },
+        }),
+        FieldKind::OneOf(kinds) => json!({
+            "oneOf": kinds.iter().map(field_schema).collect::<Vec<_>>(),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(fields: Value) -> Value {
+        let mut config = json!({
+            "platform": PLATFORM_NAME,
+            "name": "Spotify",
+            "client_id": "id",
+            "client_secret": "secret",
+            "refresh_token": "token",
+        });
+        if let (Some(config), Some(fields)) = (config.as_object_mut(), fields.as_object()) {
+            config.extend(fields.clone());
+        }
+        config
+    }
+
+    fn errors(fields: Value) -> Vec<(String, String)> {
+        match Config::from_value(config(fields)) {
+            Ok(_) => Vec::new(),
+            Err(errors) => errors.into_iter().map(|e| (e.field, e.message)).collect(),
+        }
+    }
+
+    fn error(field: &str, message: &str) -> (String, String) {
+        (field.to_owned(), message.to_owned())
+    }
+
+    #[test]
+    fn valid_config() {
+        let config = Config::from_value(config(json!({
+            "refresh_rate": 5000,
+            "devices": {
+                "Kitchen": {
+                    "min_volume": 10,
+                    "max_volume": 80,
+                    "default_volume": 30,
+                    "volume_curve": [[0, 0], [50, 20], [100, 80]],
+                },
+            },
+            "alarms": [{ "name": "Wake up", "device": "Kitchen", "context_uri": "spotify:playlist:1", "time": "07:30" }],
+            "presets": [{ "name": "Jazz", "playlist": "Jazz" }],
+        })));
+        let config = match config {
+            Ok(config) => config,
+            Err(errors) => panic!("unexpected errors: {:?}", errors),
+        };
+
+        assert_eq!(config.refresh_rate, Some(5000));
+        assert_eq!(
+            config.device_config("id", "Kitchen").default_volume,
+            Some(30)
+        );
+        assert_eq!(config.device_config("id", "Bedroom").default_volume, None);
+    }
+
+    #[test]
+    fn missing_and_invalid_fields() {
+        assert_eq!(
+            errors(json!({ "client_id": null, "refresh_rate": 500, "devices": [] })),
+            vec![
+                error("client_id", "is missing"),
+                error("refresh_rate", "must be at least 1000"),
+                error("devices", "must be an object"),
+            ]
+        );
+    }
+
+    #[test]
+    fn contradicting_volume_limits() {
+        assert_eq!(
+            errors(json!({ "devices": { "Kitchen": { "min_volume": 60, "max_volume": 40 } } })),
+            vec![error(
+                "devices.Kitchen.min_volume",
+                "must not be greater than `max_volume`"
+            )]
+        );
+        assert_eq!(
+            errors(json!({ "devices": { "Kitchen": { "max_volume": 40, "default_volume": 50 } } })),
+            vec![error(
+                "devices.Kitchen.default_volume",
+                "must be between 0 and 40"
+            )]
+        );
+        assert_eq!(
+            errors(json!({ "devices": { "Kitchen": { "volume_cap": 40, "default_volume": 50 } } })),
+            vec![error(
+                "devices.Kitchen.default_volume",
+                "must not be greater than `volume_cap`"
+            )]
+        );
+    }
+
+    #[test]
+    fn unsorted_volume_curve() {
+        assert_eq!(
+            errors(json!({ "devices": { "Kitchen": { "volume_curve": [[50, 20], [0, 0]] } } })),
+            vec![error(
+                "devices.Kitchen.volume_curve",
+                "breakpoints must be sorted by slider value and must not decrease in volume"
+            )]
+        );
+        assert!(
+            errors(json!({ "devices": { "Kitchen": { "volume_curve": "logarithmic" } } }))
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn parse_valid_times() {
+        assert_eq!(parse_time("00:00"), Some((0, 0)));
+        assert_eq!(parse_time("07:30"), Some((7, 30)));
+        assert_eq!(parse_time("23:59"), Some((23, 59)));
+    }
+
+    #[test]
+    fn parse_invalid_times() {
+        for time in &[
+            "24:00", "12:60", "7:30", "07:3", "0730", "07:30:00", "+7:30", "07:-1", "aa:bb", "",
+        ] {
+            assert_eq!(parse_time(time), None, "{}", time);
+        }
+    }
+
+    #[test]
+    fn invalid_alarms() {
+        let alarm =
+            json!({ "name": "Wake up", "device": "Kitchen", "context_uri": "spotify:playlist:1" });
+
+        assert_eq!(
+            errors(json!({ "alarms": [alarm, alarm] })),
+            vec![error("alarms.1.name", "\"Wake up\" is used more than once")]
+        );
+
+        let mut late = alarm.clone();
+        late["time"] = json!("25:00");
+        assert_eq!(
+            errors(json!({ "alarms": [late] })),
+            vec![error(
+                "alarms.0.time",
+                "must be a time of day formatted as HH:MM"
+            )]
+        );
+    }
+
+    #[test]
+    fn invalid_presets() {
+        assert_eq!(
+            errors(
+                json!({ "presets": [{ "name": "Jazz", "playlist": "Jazz", "artist": "Miles Davis" }] })
+            ),
+            vec![error(
+                "presets.0",
+                "must set exactly one of `uri`, `playlist`, `artist` or `search`"
+            )]
+        );
+        assert_eq!(
+            errors(
+                json!({ "presets": [{ "name": "Jazz", "uri": "spotify:album:1", "action": "queue" }] })
+            ),
+            vec![error(
+                "presets.0.uri",
+                "must be a track or episode to be queued"
+            )]
+        );
+        assert_eq!(
+            errors(json!({ "presets": [{ "name": "Jazz", "uri": "jazz" }] })),
+            vec![error(
+                "presets.0.uri",
+                "must be a Spotify URI like spotify:track:<id>"
+            )]
+        );
+    }
+}
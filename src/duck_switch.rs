@@ -0,0 +1,128 @@
+//! Momentary switch ducking playback.
+
+use js_sys::Function;
+use std::any::Any;
+use std::rc::Rc;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::{spawn_local, JsFuture};
+
+use crate::config::DuckingConfig;
+use crate::node_fetch::error_message;
+use crate::poll_scheduler::PollScheduler;
+use crate::spotify_accessory::{create_switch, respond, Accessory, UUIDGen};
+use crate::spotify_api::SpotifyApi;
+use crate::spotify_platform::Service;
+
+/// Seconds after which the volume is restored if not configured.
+const DEFAULT_DURATION: u32 = 30;
+
+#[derive(Debug)]
+/// Represents the switch ducking playback, On while ducked.
+pub struct DuckSwitch {
+    /// Name of the switch
+    name: String,
+    /// Accessory to be registered to Homebridge
+    accessory: Accessory,
+    /// Switch service
+    service: Service,
+    /// Characteristic handlers, released when the switch is dropped
+    handlers: Vec<Box<dyn Any>>,
+}
+
+impl DuckSwitch {
+    pub fn new(
+        config: DuckingConfig,
+        api: Rc<SpotifyApi>,
+        scheduler: Rc<PollScheduler>,
+    ) -> DuckSwitch {
+        let accessory = Accessory::new(
+            &config.name,
+            &UUIDGen::generate(&format!("ducking:{}", config.name)),
+        );
+        let service = create_switch(&config.name);
+        accessory.add_service(&service);
+
+        let mut switch = DuckSwitch {
+            name: config.name.clone(),
+            accessory,
+            service,
+            handlers: Vec::new(),
+        };
+
+        switch.apply_characteristics(&config, api, scheduler);
+        switch
+    }
+
+    /// Return the switch name.
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    /// Return the Homebridge accessory.
+    pub fn get_accessory(&self) -> &Accessory {
+        &self.accessory
+    }
+
+    /// Setup up Homebridge characteristics.
+    fn apply_characteristics(
+        &mut self,
+        config: &DuckingConfig,
+        api: Rc<SpotifyApi>,
+        scheduler: Rc<PollScheduler>,
+    ) {
+        let volume = config.volume;
+        let duration = config.duration.unwrap_or(DEFAULT_DURATION) * 1000;
+
+        let get_api = Rc::clone(&api);
+        let get_on = Closure::wrap(Box::new(move |callback: Function| {
+            respond(&callback, Ok(JsValue::from(get_api.is_ducked())));
+        }) as Box<dyn FnMut(Function)>);
+
+        let set_api = Rc::clone(&api);
+        let service = self.service.clone();
+        let set_on = Closure::wrap(Box::new(move |on: bool, callback: Function| {
+            scheduler.notify_command();
+
+            let api = Rc::clone(&set_api);
+            let service = service.clone();
+
+            spawn_local(async move {
+                let result = if on {
+                    JsFuture::from(api.duck(volume, duration)).await
+                } else {
+                    JsFuture::from(api.unduck()).await
+                };
+                let ducked = result.as_ref().ok().and_then(JsValue::as_bool);
+
+                respond(
+                    &callback,
+                    result
+                        .map(|_| JsValue::from(on))
+                        .map_err(|e| error_message(&e)),
+                );
+
+                // nothing was playing, so there is nothing to restore
+                if on && ducked == Some(false) {
+                    service.update_characteristic("On", &JsValue::from(false));
+                }
+            });
+        }) as Box<dyn FnMut(bool, Function)>);
+
+        // the switch turns itself off once the volume is restored
+        let service = self.service.clone();
+        let ducking_changed = Closure::wrap(Box::new(move |ducked: bool| {
+            service.update_characteristic("On", &JsValue::from(ducked));
+        }) as Box<dyn FnMut(bool)>);
+        api.add_ducking_listener(ducking_changed.as_ref().unchecked_ref::<Function>().clone());
+
+        self.service
+            .get_characteristic("On")
+            .on("set", set_on.as_ref().unchecked_ref())
+            .on("get", get_on.as_ref().unchecked_ref());
+
+        self.handlers.push(Box::new(get_on));
+        self.handlers.push(Box::new(set_on));
+        self.handlers.push(Box::new(ducking_changed));
+    }
+}
@@ -0,0 +1,284 @@
+//! Temporarily lowers the volume, e.g. while the doorbell rings.
+
+use js_sys::Function;
+use std::cell::RefCell;
+use std::rc::Rc;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::{spawn_local, JsFuture};
+
+use crate::logger;
+use crate::spotify_api::{SpotifyApi, SpotifyPlayback};
+use crate::state_store::StateStore;
+use crate::timer::Timeout;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+/// Represents the playback state before ducking.
+pub struct Snapshot {
+    /// Device that was playing
+    device_id: String,
+    /// Volume to restore, `None` if the device doesn't report its volume
+    volume: Option<u32>,
+    /// Volume while ducked, `None` if playback got paused instead
+    ducked_volume: Option<u32>,
+}
+
+#[derive(Debug)]
+/// Ducking state shared by all clones of the API.
+pub struct Ducking {
+    /// Playback state to restore, present while ducked
+    snapshot: Option<Snapshot>,
+    /// Timeout restoring the playback state
+    timeout: Option<Timeout>,
+    /// Duration requested while the volume is being lowered, present until ducked
+    pending: Option<u32>,
+    /// Whether restoring was requested while the volume was being lowered
+    restore_requested: bool,
+    /// Functions called with `true` when ducking starts and `false` once restored
+    listeners: Vec<Function>,
+    /// Keeps the playback state to restore across restarts
+    store: Rc<StateStore>,
+}
+
+impl Ducking {
+    pub fn new(store: Rc<StateStore>) -> Ducking {
+        Ducking {
+            snapshot: None,
+            timeout: None,
+            pending: None,
+            restore_requested: false,
+            listeners: Vec::new(),
+            store,
+        }
+    }
+
+    /// Return whether playback is currently ducked.
+    pub fn is_ducked(&self) -> bool {
+        self.snapshot.is_some()
+    }
+
+    /// Register a function getting called whenever ducking starts or ends.
+    pub fn add_listener(&mut self, listener: Function) {
+        self.listeners.push(listener);
+    }
+
+    fn notify(&self, ducked: bool) {
+        for listener in &self.listeners {
+            listener.call1(&JsValue::NULL, &JsValue::from(ducked)).ok();
+        }
+    }
+}
+
+/// Lower the volume of the playing device to `volume`, or pause it if no volume is provided,
+/// and restore the previous state after `duration` milliseconds.
+/// Ducking again while ducked only extends the duration.
+pub async fn duck(
+    api: &SpotifyApi,
+    ducking: &Rc<RefCell<Ducking>>,
+    volume: Option<u32>,
+    duration: u32,
+) -> Result<bool, JsValue> {
+    {
+        let mut state = ducking.borrow_mut();
+
+        if state.is_ducked() {
+            drop(state);
+            schedule_restore(api, ducking, duration);
+            return Ok(true);
+        }
+
+        // mark ducking as in progress before the first request, otherwise a second duck
+        // would capture the lowered volume as the volume to restore
+        if let Some(pending) = state.pending.as_mut() {
+            *pending = duration;
+            return Ok(true);
+        }
+        state.pending = Some(duration);
+        state.restore_requested = false;
+    }
+
+    let result = lower(api, volume).await;
+    let (duration, restore_requested) = {
+        let mut state = ducking.borrow_mut();
+        let duration = state.pending.take().unwrap_or(duration);
+        (duration, std::mem::take(&mut state.restore_requested))
+    };
+
+    let snapshot = match result? {
+        Some(snapshot) => snapshot,
+        None => return Ok(false),
+    };
+
+    {
+        let mut state = ducking.borrow_mut();
+        state.store.record_ducking(Some(snapshot.clone()));
+        state.snapshot = Some(snapshot);
+        state.notify(true);
+    }
+
+    if restore_requested {
+        restore(api, ducking).await?;
+    } else {
+        schedule_restore(api, ducking, duration);
+    }
+    Ok(true)
+}
+
+/// Lower the volume of the playing device, resolves to the state to restore
+/// or `None` if nothing is playing.
+async fn lower(api: &SpotifyApi, volume: Option<u32>) -> Result<Option<Snapshot>, JsValue> {
+    #[allow(deprecated)]
+    let playback: Option<SpotifyPlayback> = JsFuture::from(api.get_playback_state())
+        .await?
+        .into_serde()
+        .unwrap_or(None);
+
+    let device = match playback {
+        Some(playback) if playback.is_playing => playback.device,
+        _ => {
+            logger::debug("Nothing is playing, nothing to duck");
+            return Ok(None);
+        }
+    };
+
+    let ducked_volume = match (volume, device.volume_percent) {
+        // don't raise the volume of a device that already plays quieter
+        (Some(volume), Some(current)) => Some(volume.min(current)),
+        // devices that don't report their volume get paused
+        _ => None,
+    };
+
+    match ducked_volume {
+        Some(volume) => JsFuture::from(api.set_volume(device.id.clone(), volume)).await?,
+        None => JsFuture::from(api.pause(device.id.clone())).await?,
+    };
+    logger::info(&format!("Ducked Spotify device {}", device.name));
+
+    Ok(Some(Snapshot {
+        device_id: device.id,
+        volume: device.volume_percent,
+        ducked_volume,
+    }))
+}
+
+/// Restore the playback state from before ducking, unless it got changed in the meantime.
+/// Restoring while the volume is still being lowered restores it once lowered.
+pub async fn restore(api: &SpotifyApi, ducking: &Rc<RefCell<Ducking>>) -> Result<(), JsValue> {
+    let snapshot = {
+        let mut ducking = ducking.borrow_mut();
+        ducking.timeout = None;
+
+        match ducking.snapshot.take() {
+            Some(snapshot) => snapshot,
+            None => {
+                if ducking.pending.is_some() {
+                    ducking.restore_requested = true;
+                }
+                return Ok(());
+            }
+        }
+    };
+    ducking.borrow().notify(false);
+
+    restore_snapshot(api, &snapshot).await?;
+
+    // the stored state is kept if restoring failed, so that a restart tries again
+    ducking.borrow().store.record_ducking(None);
+    Ok(())
+}
+
+/// Restore the volume or resume playback of the ducked device.
+async fn restore_snapshot(api: &SpotifyApi, snapshot: &Snapshot) -> Result<(), JsValue> {
+    match snapshot.ducked_volume {
+        Some(ducked_volume) => {
+            // the volume is restored even if playback got paused in the meantime,
+            // otherwise the next resume would play at the ducked volume
+            let device = match api.find_device(&snapshot.device_id).await? {
+                Some(device) => device,
+                None => {
+                    logger::info("Ducked Spotify device is no longer available, not restoring it");
+                    return Ok(());
+                }
+            };
+
+            if device.volume_percent != Some(ducked_volume) {
+                logger::info("Volume changed while ducked, not restoring it");
+                return Ok(());
+            }
+            if let Some(volume) = snapshot.volume {
+                JsFuture::from(api.set_volume(device.id, volume)).await?;
+            }
+            logger::info(&format!("Restored Spotify device {}", device.name));
+        }
+        None => {
+            #[allow(deprecated)]
+            let playback: Option<SpotifyPlayback> = JsFuture::from(api.get_playback_state())
+                .await?
+                .into_serde()
+                .unwrap_or(None);
+
+            // leave playback alone if it moved to another device or was resumed from elsewhere
+            let playback = match playback {
+                Some(playback) if playback.device.id == snapshot.device_id => playback,
+                _ => {
+                    logger::info("Playback moved to another device while ducked, not restoring it");
+                    return Ok(());
+                }
+            };
+
+            if playback.is_playing {
+                logger::info("Playback resumed while ducked, not restoring it");
+                return Ok(());
+            }
+            JsFuture::from(api.play(snapshot.device_id.clone())).await?;
+            logger::info(&format!("Restored Spotify device {}", playback.device.name));
+        }
+    }
+
+    Ok(())
+}
+
+/// Start restoring right away instead of waiting for the timeout, e.g. when Homebridge
+/// shuts down. The stored state is only cleared once restored, so a restart finishes it.
+pub fn restore_now(api: &SpotifyApi, ducking: &Rc<RefCell<Ducking>>) {
+    if ducking.borrow().is_ducked() {
+        let api = api.clone();
+        let ducking = Rc::clone(ducking);
+
+        spawn_local(async move {
+            restore(&api, &ducking).await.ok();
+        });
+    } else {
+        ducking.borrow_mut().timeout = None;
+    }
+}
+
+/// Restore the volume that was still lowered when Homebridge stopped.
+/// Paused playback isn't resumed, as the restart might have taken a while.
+pub fn resume(api: &SpotifyApi, ducking: &Rc<RefCell<Ducking>>) {
+    let snapshot = match ducking.borrow().store.ducking() {
+        Some(snapshot) => snapshot,
+        None => return,
+    };
+
+    if snapshot.ducked_volume.is_none() {
+        logger::info("Playback was paused while ducked before restarting, not resuming it");
+        ducking.borrow().store.record_ducking(None);
+        return;
+    }
+
+    ducking.borrow_mut().snapshot = Some(snapshot);
+    restore_now(api, ducking);
+}
+
+/// Restore the playback state after `duration` milliseconds, replacing an earlier timeout.
+fn schedule_restore(api: &SpotifyApi, ducking: &Rc<RefCell<Ducking>>, duration: u32) {
+    let api = api.clone();
+    let shared = Rc::clone(ducking);
+
+    let timeout = Timeout::new(duration, move || {
+        spawn_local(async move {
+            restore(&api, &shared).await.ok();
+        });
+    });
+    ducking.borrow_mut().timeout = Some(timeout);
+}
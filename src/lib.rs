@@ -4,7 +4,26 @@ extern crate base64;
 extern crate js_sys;
 extern crate web_sys;
 
+mod alarm;
+pub mod config;
+mod duck_switch;
+mod ducking;
+mod like_switch;
+mod logger;
 mod node_fetch;
+mod poll_scheduler;
+mod preset_resolver;
+mod preset_switch;
+mod skip_switches;
+mod sleep_timer;
+mod snapshot;
+mod snapshot_switch;
 mod spotify_accessory;
 mod spotify_api;
 mod spotify_platform;
+mod spotify_state;
+mod state_store;
+mod timer;
+mod volume_curve;
+mod volume_debouncer;
+mod volume_ramp;
@@ -0,0 +1,218 @@
+//! Switch saving the track or episode playing on a device to the library.
+
+use js_sys::Function;
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::{spawn_local, JsFuture};
+
+use crate::config::LikeSwitchMode;
+use crate::logger;
+use crate::node_fetch::error_message;
+use crate::spotify_accessory::{
+    create_switch_with_subtype, respond, Accessory, BUTTON_RESET_DELAY,
+};
+use crate::spotify_api::{SpotifyApi, SpotifyPlayback};
+use crate::spotify_platform::Service;
+use crate::spotify_state::SharedState;
+use crate::timer::sleep;
+
+#[derive(Debug)]
+/// Library state of the item playing on the device, shared with the characteristic handlers.
+struct Library {
+    /// Switch service
+    service: Service,
+    /// API to control Spotify
+    api: Rc<SpotifyApi>,
+    /// ID of the device the switch belongs to
+    device_id: String,
+    /// URI of the item last checked, `None` if nothing saveable is playing
+    checked: RefCell<Option<String>>,
+    /// Whether the item last checked is saved
+    liked: Cell<bool>,
+}
+
+#[derive(Debug)]
+/// Represents the like switch of a device.
+pub struct LikeSwitch {
+    /// Whether the switch turns itself off or reflects the library
+    mode: LikeSwitchMode,
+    /// Spotify state retrieved during the last platform refresh
+    state: SharedState,
+    /// Library state shared with the handlers
+    library: Rc<Library>,
+    /// Characteristic handlers, released when the switch is dropped
+    handlers: Vec<Box<dyn Any>>,
+}
+
+impl LikeSwitch {
+    /// Add the like switch to the accessory of the device.
+    pub fn new(
+        name: &str,
+        device_id: String,
+        mode: LikeSwitchMode,
+        accessory: &Accessory,
+        api: Rc<SpotifyApi>,
+        state: SharedState,
+    ) -> LikeSwitch {
+        let service = create_switch_with_subtype(&format!("{} Like", name), "like");
+        accessory.add_service(&service);
+
+        let mut switch = LikeSwitch {
+            mode,
+            state,
+            library: Rc::new(Library {
+                service,
+                api,
+                device_id,
+                checked: RefCell::new(None),
+                liked: Cell::new(false),
+            }),
+            handlers: Vec::new(),
+        };
+
+        switch.apply_characteristics();
+        switch
+    }
+
+    /// Check whether the item playing on the device is saved once it changes.
+    pub fn update_characteristics(&self) {
+        if self.mode != LikeSwitchMode::Stateful {
+            return;
+        }
+
+        let uri = {
+            let state = self.state.borrow();
+            state
+                .playback
+                .as_ref()
+                .filter(|p| p.device.id == self.library.device_id)
+                .and_then(|p| p.item.as_ref())
+                .and_then(|item| item.saveable_uri())
+                .map(str::to_owned)
+        };
+
+        if *self.library.checked.borrow() == uri {
+            return;
+        }
+        self.library.checked.replace(uri.clone());
+
+        match uri {
+            Some(uri) => {
+                let library = Rc::clone(&self.library);
+                spawn_local(async move {
+                    let result = library.api.is_saved(&uri).await;
+
+                    // ignore results for items that stopped playing in the meantime
+                    if library.checked.borrow().as_deref() != Some(uri.as_str()) {
+                        return;
+                    }
+
+                    match result {
+                        Ok(liked) => library.report(liked),
+                        // check the item again during the next refresh
+                        Err(_) => {
+                            library.checked.replace(None);
+                        }
+                    }
+                });
+            }
+            None => self.library.report(false),
+        }
+    }
+
+    /// Setup up Homebridge characteristics.
+    fn apply_characteristics(&mut self) {
+        let mode = self.mode;
+
+        let library = Rc::clone(&self.library);
+        let get_on = Closure::wrap(Box::new(move |callback: Function| {
+            let on = mode == LikeSwitchMode::Stateful && library.liked.get();
+            respond(&callback, Ok(JsValue::from(on)));
+        }) as Box<dyn FnMut(Function)>);
+
+        let library = Rc::clone(&self.library);
+        let set_on = Closure::wrap(Box::new(move |on: bool, callback: Function| {
+            if mode == LikeSwitchMode::Momentary && !on {
+                respond(&callback, Ok(JsValue::from(false)));
+                return;
+            }
+
+            let library = Rc::clone(&library);
+
+            spawn_local(async move {
+                let result = library.set_liked(on).await;
+                let saved = result.as_ref().ok().copied();
+
+                respond(
+                    &callback,
+                    result
+                        .map(|_| JsValue::from(on))
+                        .map_err(|e| error_message(&e)),
+                );
+
+                match mode {
+                    // the switch acts like a button
+                    LikeSwitchMode::Momentary => {
+                        sleep(BUTTON_RESET_DELAY).await;
+                        library.report(false);
+                    }
+                    // nothing was playing, so nothing got saved
+                    LikeSwitchMode::Stateful if saved == Some(false) => library.report(false),
+                    LikeSwitchMode::Stateful => {}
+                }
+            });
+        }) as Box<dyn FnMut(bool, Function)>);
+
+        self.library
+            .service
+            .get_characteristic("On")
+            .on("set", set_on.as_ref().unchecked_ref())
+            .on("get", get_on.as_ref().unchecked_ref());
+
+        self.handlers.push(Box::new(get_on));
+        self.handlers.push(Box::new(set_on));
+    }
+}
+
+impl Library {
+    /// Save or remove the item playing on the device, resolves to whether there was one.
+    async fn set_liked(&self, liked: bool) -> Result<bool, JsValue> {
+        #[allow(deprecated)]
+        let playback: Option<SpotifyPlayback> = JsFuture::from(self.api.get_playback_state())
+            .await?
+            .into_serde()
+            .unwrap_or(None);
+
+        let uri = playback
+            .filter(|p| p.device.id == self.device_id)
+            .and_then(|p| p.item)
+            .and_then(|item| item.saveable_uri().map(str::to_owned));
+
+        let uri = match uri {
+            Some(uri) => uri,
+            None => {
+                logger::warn(&format!(
+                    "Nothing is playing on Spotify device {} that can be saved",
+                    self.device_id
+                ));
+                return Ok(false);
+            }
+        };
+
+        self.api.set_saved(&uri, liked).await?;
+
+        self.checked.replace(Some(uri));
+        self.liked.set(liked);
+        Ok(true)
+    }
+
+    /// Push whether the current item is saved to HomeKit.
+    fn report(&self, liked: bool) {
+        self.liked.set(liked);
+        self.service
+            .update_characteristic("On", &JsValue::from(liked));
+    }
+}
@@ -0,0 +1,158 @@
+//! Logging through the Homebridge logger.
+
+use std::cell::{Cell, RefCell};
+use wasm_bindgen::prelude::*;
+use web_sys::console;
+
+/// Prefix for messages logged before the Homebridge logger is available.
+const LOG_PREFIX: &str = "[homebridge-rusty-spotify]";
+/// Keys of values that must not show up in logs.
+const SECRET_KEYS: [&str; 3] = ["access_token", "refresh_token", "client_secret"];
+
+#[wasm_bindgen]
+extern "C" {
+    #[derive(Clone, Debug)]
+    pub type Log;
+
+    #[wasm_bindgen(method)]
+    fn info(this: &Log, message: &str);
+
+    #[wasm_bindgen(method)]
+    fn warn(this: &Log, message: &str);
+
+    #[wasm_bindgen(method)]
+    fn error(this: &Log, message: &str);
+
+    #[wasm_bindgen(method)]
+    fn debug(this: &Log, message: &str);
+}
+
+thread_local! {
+    /// Homebridge logger passed to the platform
+    static LOG: RefCell<Option<Log>> = const { RefCell::new(None) };
+    /// Whether debug messages are logged with the info level
+    static DEBUG: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Route all messages through the Homebridge logger.
+pub fn init(log: Log) {
+    LOG.with(|l| l.replace(Some(log)));
+}
+
+/// If `debug` is enabled, debug messages are logged even if Homebridge doesn't run in debug mode.
+pub fn set_debug(debug: bool) {
+    DEBUG.with(|d| d.set(debug));
+}
+
+/// Return whether verbose debug logging is enabled.
+pub fn debug_enabled() -> bool {
+    DEBUG.with(|d| d.get())
+}
+
+/// Log an informational message.
+pub fn info(message: &str) {
+    LOG.with(|l| match l.borrow().as_ref() {
+        Some(log) => log.info(message),
+        None => console::info_1(&format!("{} {}", LOG_PREFIX, message).into()),
+    });
+}
+
+/// Log a warning.
+pub fn warn(message: &str) {
+    LOG.with(|l| match l.borrow().as_ref() {
+        Some(log) => log.warn(message),
+        None => console::warn_1(&format!("{} {}", LOG_PREFIX, message).into()),
+    });
+}
+
+/// Log an error.
+pub fn error(message: &str) {
+    LOG.with(|l| match l.borrow().as_ref() {
+        Some(log) => log.error(message),
+        None => console::error_1(&format!("{} {}", LOG_PREFIX, message).into()),
+    });
+}
+
+/// Log a debug message.
+pub fn debug(message: &str) {
+    if debug_enabled() {
+        info(&format!("[debug] {}", message));
+    } else {
+        LOG.with(|l| {
+            if let Some(log) = l.borrow().as_ref() {
+                log.debug(message)
+            }
+        });
+    }
+}
+
+/// Mask tokens and secrets in form encoded or JSON text.
+pub fn redact(text: &str) -> String {
+    let mut redacted = text.to_owned();
+
+    for key in SECRET_KEYS.iter() {
+        let mut search_from = 0;
+
+        while let Some(position) = redacted[search_from..].find(key) {
+            let key_end = search_from + position + key.len();
+
+            // skip the separator between key and value, e.g. `=` or `": "`
+            let value_start = key_end
+                + redacted[key_end..]
+                    .find(|c: char| !['"', ':', '=', ' '].contains(&c))
+                    .unwrap_or(redacted.len() - key_end);
+            let value_end = value_start
+                + redacted[value_start..]
+                    .find(['"', '&', ',', '}'])
+                    .unwrap_or(redacted.len() - value_start);
+
+            if value_end > value_start {
+                redacted.replace_range(value_start..value_end, "<redacted>");
+            }
+            search_from = value_start;
+        }
+    }
+
+    redacted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redact_form_encoded() {
+        assert_eq!(
+            redact("grant_type=refresh_token&refresh_token=abc123&client_secret=s3cr3t"),
+            "grant_type=refresh_token&refresh_token=<redacted>&client_secret=<redacted>"
+        );
+    }
+
+    #[test]
+    fn redact_json() {
+        assert_eq!(
+            redact(r#"{"access_token": "abc", "token_type": "Bearer", "expires_in": 3600}"#),
+            r#"{"access_token": "<redacted>", "token_type": "Bearer", "expires_in": 3600}"#
+        );
+        assert_eq!(
+            redact(r#"{"access_token":"abc","refresh_token":"def"}"#),
+            r#"{"access_token":"<redacted>","refresh_token":"<redacted>"}"#
+        );
+    }
+
+    #[test]
+    fn redact_repeated_and_trailing_keys() {
+        assert_eq!(
+            redact("access_token=a&access_token=b"),
+            "access_token=<redacted>&access_token=<redacted>"
+        );
+        assert_eq!(redact("refresh_token="), "refresh_token=");
+        assert_eq!(redact("client_secret"), "client_secret");
+    }
+
+    #[test]
+    fn redact_without_secrets() {
+        let text = r#"{"device": {"id": "abc", "volume_percent": 50}}"#;
+        assert_eq!(redact(text), text);
+    }
+}
@@ -1,10 +1,11 @@
-///! Helper methods for using node-fetch.
-use js_sys::{Array, Function, Promise};
+//! Helper methods for using node-fetch.
+use js_sys::{Array, Error, Function, Promise, Reflect, JSON};
 use std::collections::HashMap;
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::JsCast;
 use wasm_bindgen_futures::JsFuture;
-use web_sys::console;
+
+use crate::logger;
 
 #[wasm_bindgen]
 extern "C" {
@@ -17,22 +18,53 @@ extern "C" {
 
     #[wasm_bindgen(method, js_name = text)]
     fn text(this: &Response) -> Promise;
+
+    #[wasm_bindgen(method, getter)]
+    fn status(this: &Response) -> u16;
+
+    #[wasm_bindgen(method, getter)]
+    fn ok(this: &Response) -> bool;
+
+    #[wasm_bindgen(method, getter)]
+    fn headers(this: &Response) -> Headers;
+
+    type Headers;
+
+    #[wasm_bindgen(method)]
+    fn get(this: &Headers, name: &str) -> Option<String>;
 }
 
+#[derive(Deserialize)]
+/// Represents the error object returned by the Spotify Web API.
+struct ErrorResponse {
+    error: ErrorDetails,
+}
+
+#[derive(Deserialize)]
+/// Details of an error returned by the Spotify Web API.
+struct ErrorDetails {
+    message: String,
+    /// Reason of a failed player command, e.g. `NO_ACTIVE_DEVICE`
+    reason: Option<String>,
+}
+
+#[derive(Clone, Copy)]
 /// Supported request methods.
 pub enum FetchMethod {
     Get,
     Post,
     Put,
+    Delete,
 }
 
 impl FetchMethod {
     /// Return the string representation of the fetch method.
     pub fn as_str(&self) -> &'static str {
         match self {
-            &FetchMethod::Get => "GET",
-            &FetchMethod::Post => "POST",
-            &FetchMethod::Put => "PUT",
+            FetchMethod::Get => "GET",
+            FetchMethod::Post => "POST",
+            FetchMethod::Put => "PUT",
+            FetchMethod::Delete => "DELETE",
         }
     }
 }
@@ -49,6 +81,7 @@ struct RequestOptions {
 }
 
 /// Perform a HTTP request with the provided options.
+#[allow(deprecated)]
 pub async fn fetch(
     url: &str,
     method: FetchMethod,
@@ -59,6 +92,24 @@ pub async fn fetch(
     // node-fetch needs to be installed
     let fetch = require("node-fetch");
 
+    if logger::debug_enabled() {
+        let traced_headers: HashMap<&String, &str> = headers
+            .iter()
+            .map(|(name, value)| match name.as_str() {
+                "Authorization" => (name, "<redacted>"),
+                _ => (name, value.as_str()),
+            })
+            .collect();
+
+        logger::debug(&format!(
+            "Request: {} {} headers: {:?} body: {}",
+            method.as_str(),
+            url,
+            traced_headers,
+            logger::redact(body)
+        ));
+    }
+
     let body = match method {
         FetchMethod::Get => None, // Request with GET/HEAD method cannot have body
         _ => Some(body.to_owned()),
@@ -66,7 +117,7 @@ pub async fn fetch(
 
     let options = RequestOptions {
         method: method.as_str().to_owned(),
-        body: body,
+        body,
         headers,
     };
 
@@ -77,22 +128,112 @@ pub async fn fetch(
     match fetch_result {
         Ok(p) => {
             let promise = Promise::from(p);
-            let resp_value = JsFuture::from(promise).await?;
+            let resp_value = JsFuture::from(promise).await.map_err(|e| {
+                logger::error(&format!("Error executing fetch request {}: {:?}", url, e));
+                e
+            })?;
             let resp: Response = resp_value.unchecked_into();
 
-            if empty_response {
+            logger::debug(&format!(
+                "Response: {} {} status: {}",
+                method.as_str(),
+                url,
+                resp.status()
+            ));
+
+            if !resp.ok() {
+                return Err(error_from_response(&resp).await);
+            }
+
+            // 204 No Content is returned e.g. if no playback is active
+            if empty_response || resp.status() == 204 {
                 Ok(JsValue::NULL)
             } else {
                 let json: JsValue = JsFuture::from(resp.json()).await?;
+
+                if logger::debug_enabled() {
+                    let text = JSON::stringify(&json).map(String::from).unwrap_or_default();
+                    logger::debug(&format!("Response body: {}", logger::redact(&text)));
+                }
+
                 Ok(json)
             }
         }
         Err(e) => {
-            console::log_1(&format!("Error executing fetch request {:?}", e).into());
+            logger::error(&format!("Error executing fetch request {}: {:?}", url, e));
             Err(JsValue::from(format!(
-                "Error executing fetch request {:?}",
-                e
+                "Error executing fetch request {}: {:?}",
+                url, e
             )))
         }
     }
 }
+
+/// Create an error with the HTTP status and the reason reported by the Spotify Web API.
+/// The status and reason are available as `status` and `reason` properties of the error,
+/// the seconds to wait after being rate limited as `retryAfter`.
+async fn error_from_response(resp: &Response) -> JsValue {
+    let status = resp.status();
+    let text = match JsFuture::from(resp.text()).await {
+        Ok(text) => text.as_string().unwrap_or_default(),
+        Err(_) => String::new(),
+    };
+
+    logger::debug(&format!("Response body: {}", logger::redact(&text)));
+
+    let details = serde_json::from_str::<ErrorResponse>(&text)
+        .map(|response| response.error)
+        .ok();
+
+    let message = match &details {
+        Some(details) => format!("Request failed with status {}: {}", status, details.message),
+        None => format!("Request failed with status {}", status),
+    };
+    let error = Error::new(&message);
+    let _ = Reflect::set(&error, &"status".into(), &JsValue::from(status));
+
+    if let Some(reason) = details.and_then(|d| d.reason) {
+        let _ = Reflect::set(&error, &"reason".into(), &JsValue::from(reason));
+    }
+
+    if let Some(retry_after) = resp
+        .headers()
+        .get("Retry-After")
+        .and_then(|value| value.trim().parse::<u32>().ok())
+    {
+        let _ = Reflect::set(&error, &"retryAfter".into(), &JsValue::from(retry_after));
+    }
+
+    error.into()
+}
+
+/// Return the message of an error returned by `fetch`.
+pub fn error_message(error: &JsValue) -> String {
+    match error.dyn_ref::<Error>() {
+        Some(error) => String::from(error.message()),
+        None => error.as_string().unwrap_or_else(|| format!("{:?}", error)),
+    }
+}
+
+/// Return the reason of a failed player command, e.g. `NO_ACTIVE_DEVICE`.
+pub fn error_reason(error: &JsValue) -> Option<String> {
+    Reflect::get(error, &"reason".into())
+        .ok()
+        .and_then(|reason| reason.as_string())
+}
+
+/// Return the HTTP status of a failed request.
+pub fn error_status(error: &JsValue) -> Option<u16> {
+    Reflect::get(error, &"status".into())
+        .ok()
+        .and_then(|status| status.as_f64())
+        .map(|status| status as u16)
+}
+
+/// Return the seconds to wait before retrying a rate limited request.
+pub fn error_retry_after(error: &JsValue) -> Option<u32> {
+    Reflect::get(error, &"retryAfter".into())
+        .ok()
+        .and_then(|seconds| seconds.as_f64())
+        .map(|seconds| seconds as u32)
+}
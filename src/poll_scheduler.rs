@@ -0,0 +1,280 @@
+//! Adaptive scheduling of the Spotify device refreshes.
+
+use js_sys::{Date, Math};
+use std::cell::{Cell, RefCell};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+
+use crate::logger;
+use crate::timer::{clear_timeout, set_timeout};
+
+/// Refresh interval while something is playing or after a user command.
+pub const FAST_REFRESH_RATE: u32 = 3 * 1000; // milliseconds
+/// Refresh interval while devices are available but nothing is playing.
+pub const SLOW_REFRESH_RATE: u32 = 10 * 1000; // milliseconds
+/// Duration the fast refresh rate is used after a user command.
+const COMMAND_BOOST_DURATION: f64 = 30.0 * 1000.0; // milliseconds
+/// Delay before refreshing after a user command so that Spotify can apply it.
+const COMMAND_REFRESH_DELAY: u32 = 1000; // milliseconds
+/// Upper bound for the refresh interval when backing off after errors.
+const MAX_BACKOFF_REFRESH_RATE: u32 = 5 * 60 * 1000; // milliseconds
+/// Maximum deviation from the computed interval, as a fraction of it.
+const JITTER: f64 = 0.1;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+/// Represents how actively Spotify is used.
+pub enum Activity {
+    /// Music is playing on some device
+    Playing,
+    /// A device is active but nothing is playing
+    Available,
+    /// No device is active
+    Idle,
+}
+
+#[derive(Clone, Debug)]
+/// Represents the refresh intervals in milliseconds.
+pub struct PollIntervals {
+    pub fast: u32,
+    pub slow: u32,
+    /// `None` pauses refreshes while nobody is listening
+    pub idle: Option<u32>,
+}
+
+#[derive(Debug)]
+/// Schedules refreshes depending on activity and errors.
+pub struct PollScheduler {
+    /// Refresh intervals
+    intervals: PollIntervals,
+    /// Number of consecutive failed refreshes
+    errors: Cell<u32>,
+    /// Timestamp until which the fast refresh rate is used
+    boost_until: Cell<f64>,
+    /// Timestamp of the pending refresh
+    due: Cell<f64>,
+    /// Handle of the pending timer
+    timer: RefCell<Option<JsValue>>,
+    /// Whether refreshes are paused until the next user command
+    paused: Cell<bool>,
+    /// Refresh to be executed
+    callback: RefCell<Option<Closure<dyn FnMut()>>>,
+    /// Whether refreshes have been stopped permanently
+    stopped: Cell<bool>,
+}
+
+impl PollScheduler {
+    pub fn new(intervals: PollIntervals) -> PollScheduler {
+        PollScheduler {
+            intervals,
+            errors: Cell::new(0),
+            boost_until: Cell::new(0.0),
+            due: Cell::new(0.0),
+            timer: RefCell::new(None),
+            paused: Cell::new(false),
+            callback: RefCell::new(None),
+            stopped: Cell::new(false),
+        }
+    }
+
+    /// Set the refresh that gets executed when the timer fires.
+    pub fn set_callback(&self, callback: Closure<dyn FnMut()>) {
+        self.callback.replace(Some(callback));
+    }
+
+    /// Run the refresh after the provided delay, replacing a pending refresh.
+    pub fn schedule(&self, delay: u32) {
+        self.cancel();
+        self.paused.set(false);
+
+        if let Some(callback) = self.callback.borrow().as_ref() {
+            let handle = set_timeout(callback.as_ref().unchecked_ref(), delay);
+            self.timer.replace(Some(handle));
+            self.due.set(Date::now() + f64::from(delay));
+        }
+    }
+
+    /// Schedule the next refresh based on the result of the last one.
+    pub fn schedule_next(&self, activity: Activity, success: bool) {
+        if success {
+            self.errors.set(0);
+        } else {
+            self.errors.set(self.errors.get() + 1);
+        }
+
+        match self.next_delay(activity, Date::now(), Math::random()) {
+            Some(delay) => self.schedule(delay),
+            None => {
+                self.cancel();
+                if !self.paused.replace(true) {
+                    logger::debug("No Spotify device is active, pausing refreshes");
+                }
+            }
+        }
+    }
+
+    /// Speed up refreshes after the user changed something from HomeKit,
+    /// resuming them if they are paused.
+    pub fn notify_command(&self) {
+        let now = Date::now();
+        self.boost_until.set(now + COMMAND_BOOST_DURATION);
+
+        let pending = self.timer.borrow().is_some();
+        if self.paused.get() || (pending && self.due.get() > now + f64::from(COMMAND_REFRESH_DELAY))
+        {
+            self.schedule(COMMAND_REFRESH_DELAY);
+        }
+    }
+
+    /// Cancel the pending refresh.
+    pub fn cancel(&self) {
+        if let Some(handle) = self.timer.replace(None) {
+            clear_timeout(&handle);
+        }
+    }
+
+    /// Cancel the pending refresh and don't schedule any further refreshes.
+    pub fn stop(&self) {
+        self.stopped.set(true);
+        self.cancel();
+        // the refresh holds a reference to the scheduler, drop it to break the cycle
+        self.callback.replace(None);
+    }
+
+    /// Return whether refreshes have been stopped.
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.get()
+    }
+
+    /// Determine the delay until the next refresh, `None` if refreshes are paused.
+    /// `random` between 0 and 1 picks the jitter.
+    fn next_delay(&self, activity: Activity, now: f64, random: f64) -> Option<u32> {
+        let errors = self.errors.get();
+
+        let delay = if errors > 0 {
+            // back off exponentially from the slow refresh rate
+            self.intervals
+                .slow
+                .saturating_mul(2u32.saturating_pow(errors.min(16)))
+                .min(MAX_BACKOFF_REFRESH_RATE)
+        } else if activity == Activity::Playing || now < self.boost_until.get() {
+            self.intervals.fast
+        } else if activity == Activity::Available {
+            self.intervals.slow
+        } else {
+            self.intervals.idle?
+        };
+
+        // spread requests so that refreshes don't happen in lockstep
+        let jitter = (random * 2.0 - 1.0) * JITTER * f64::from(delay);
+        Some((f64::from(delay) + jitter).max(0.0) as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scheduler(idle: Option<u32>) -> PollScheduler {
+        PollScheduler::new(PollIntervals {
+            fast: 3000,
+            slow: 10000,
+            idle,
+        })
+    }
+
+    #[test]
+    fn delay_depends_on_activity() {
+        let scheduler = scheduler(Some(60000));
+
+        assert_eq!(
+            scheduler.next_delay(Activity::Playing, 0.0, 0.5),
+            Some(3000)
+        );
+        assert_eq!(
+            scheduler.next_delay(Activity::Available, 0.0, 0.5),
+            Some(10000)
+        );
+        assert_eq!(scheduler.next_delay(Activity::Idle, 0.0, 0.5), Some(60000));
+    }
+
+    #[test]
+    fn paused_while_idle_without_idle_interval() {
+        let scheduler = scheduler(None);
+
+        assert_eq!(scheduler.next_delay(Activity::Idle, 0.0, 0.5), None);
+        assert_eq!(
+            scheduler.next_delay(Activity::Available, 0.0, 0.5),
+            Some(10000)
+        );
+    }
+
+    #[test]
+    fn fast_after_command() {
+        let scheduler = scheduler(None);
+        scheduler.boost_until.set(1000.0 + COMMAND_BOOST_DURATION);
+
+        assert_eq!(
+            scheduler.next_delay(Activity::Idle, 1000.0, 0.5),
+            Some(3000)
+        );
+        assert_eq!(
+            scheduler.next_delay(Activity::Available, 1000.0 + COMMAND_BOOST_DURATION, 0.5),
+            Some(10000)
+        );
+    }
+
+    #[test]
+    fn backs_off_after_errors() {
+        let scheduler = scheduler(None);
+
+        let delays: Vec<_> = (1..=6)
+            .map(|errors| {
+                scheduler.errors.set(errors);
+                scheduler.next_delay(Activity::Playing, 0.0, 0.5)
+            })
+            .collect();
+        assert_eq!(
+            delays,
+            vec![
+                Some(20000),
+                Some(40000),
+                Some(80000),
+                Some(160000),
+                Some(MAX_BACKOFF_REFRESH_RATE),
+                Some(MAX_BACKOFF_REFRESH_RATE)
+            ]
+        );
+
+        // backing off continues while idle
+        assert_eq!(
+            scheduler.next_delay(Activity::Idle, 0.0, 0.5),
+            Some(MAX_BACKOFF_REFRESH_RATE)
+        );
+
+        scheduler.errors.set(u32::MAX);
+        assert_eq!(
+            scheduler.next_delay(Activity::Playing, 0.0, 0.5),
+            Some(MAX_BACKOFF_REFRESH_RATE)
+        );
+    }
+
+    #[test]
+    fn jitter_stays_within_bounds() {
+        let scheduler = scheduler(None);
+
+        assert_eq!(
+            scheduler.next_delay(Activity::Available, 0.0, 0.0),
+            Some(9000)
+        );
+        assert_eq!(
+            scheduler.next_delay(Activity::Available, 0.0, 1.0),
+            Some(11000)
+        );
+
+        for step in 0..=100 {
+            let random = f64::from(step) / 100.0;
+            let delay = scheduler.next_delay(Activity::Playing, 0.0, random);
+            assert!(delay.is_some_and(|delay| (2700..=3300).contains(&delay)));
+        }
+    }
+}
@@ -0,0 +1,153 @@
+//! Resolves presets configured by name to Spotify URIs.
+
+use wasm_bindgen::prelude::*;
+
+use crate::config::PresetConfig;
+use crate::logger;
+use crate::spotify_api::{SpotifyApi, SpotifyEntry};
+
+/// Number of artists searched for when looking up an artist by name.
+const ARTIST_SEARCH_LIMIT: usize = 10;
+
+#[derive(Debug)]
+/// Represents the content a preset refers to.
+pub enum PresetTarget {
+    /// Spotify URI, used as is
+    Uri(String),
+    /// Name of one of the user's playlists
+    Playlist(String),
+    /// Name of an artist
+    Artist(String),
+    /// Search query and the type of item searched for
+    Search { query: String, kind: String },
+}
+
+impl PresetTarget {
+    /// Return the content configured for a preset.
+    pub fn from_config(config: &PresetConfig) -> PresetTarget {
+        if let Some(playlist) = &config.playlist {
+            PresetTarget::Playlist(playlist.clone())
+        } else if let Some(artist) = &config.artist {
+            PresetTarget::Artist(artist.clone())
+        } else if let Some(query) = &config.search {
+            PresetTarget::Search {
+                query: query.clone(),
+                kind: config
+                    .search_type
+                    .clone()
+                    .unwrap_or_else(|| "track".to_owned()),
+            }
+        } else {
+            // the config validation makes sure that one of them is set
+            PresetTarget::Uri(config.uri.clone().unwrap_or_default())
+        }
+    }
+
+    /// Return the URI if no lookup is needed.
+    pub fn uri(&self) -> Option<&str> {
+        match self {
+            PresetTarget::Uri(uri) => Some(uri),
+            _ => None,
+        }
+    }
+
+    /// Return the key the resolved URI is remembered by, e.g. `playlist:Dinner`.
+    pub fn cache_key(&self) -> String {
+        match self {
+            PresetTarget::Uri(uri) => uri.clone(),
+            PresetTarget::Playlist(name) => format!("playlist:{}", name),
+            PresetTarget::Artist(name) => format!("artist:{}", name),
+            PresetTarget::Search { query, kind } => format!("search:{}:{}", kind, query),
+        }
+    }
+}
+
+/// Look up the URI of a preset target.
+/// Resolves to `None` if nothing was found, ambiguous names resolve to the first match.
+pub async fn resolve(
+    api: &SpotifyApi,
+    preset: &str,
+    target: &PresetTarget,
+) -> Result<Option<String>, JsValue> {
+    let entry = match target {
+        PresetTarget::Uri(uri) => return Ok(Some(uri.clone())),
+        PresetTarget::Playlist(name) => {
+            let playlists = api.playlists(None).await?;
+            pick(preset, "playlist", name, matching(playlists, name))
+        }
+        PresetTarget::Artist(name) => {
+            let mut results = api
+                .search_items(name, &["artist"], ARTIST_SEARCH_LIMIT)
+                .await?;
+            let artists = results.remove("artists").unwrap_or_default();
+            pick(preset, "artist", name, matching(artists, name))
+        }
+        PresetTarget::Search { query, kind } => {
+            let mut results = api.search_items(query, &[kind.as_str()], 1).await?;
+            let entry = results
+                .remove(&format!("{}s", kind))
+                .and_then(|results| results.into_iter().next());
+
+            match &entry {
+                Some(entry) => logger::info(&format!(
+                    "Preset {}: search \"{}\" found {} {} ({})",
+                    preset, query, kind, entry.name, entry.uri
+                )),
+                None => logger::warn(&format!(
+                    "Preset {}: search \"{}\" found no {}",
+                    preset, query, kind
+                )),
+            }
+            entry
+        }
+    };
+
+    Ok(entry.map(|entry| entry.uri))
+}
+
+/// Return the entries named `name`, ignoring case.
+fn matching(entries: Vec<SpotifyEntry>, name: &str) -> Vec<SpotifyEntry> {
+    let name = name.trim().to_lowercase();
+
+    entries
+        .into_iter()
+        .filter(|entry| entry.name.trim().to_lowercase() == name)
+        .collect()
+}
+
+/// Return the first match, logging if there is none or more than one.
+fn pick(preset: &str, kind: &str, name: &str, matches: Vec<SpotifyEntry>) -> Option<SpotifyEntry> {
+    match matches.len() {
+        0 => logger::warn(&format!(
+            "Preset {}: no {} named \"{}\" found",
+            preset, kind, name
+        )),
+        1 => logger::debug(&format!(
+            "Preset {}: {} \"{}\" is {}",
+            preset, kind, name, matches[0].uri
+        )),
+        _ => logger::warn(&format!(
+            "Preset {}: {} name \"{}\" is ambiguous, using {} of {}: {}",
+            preset,
+            kind,
+            name,
+            matches[0].uri,
+            matches.len(),
+            matches.iter().map(describe).collect::<Vec<_>>().join(", ")
+        )),
+    }
+
+    matches.into_iter().next()
+}
+
+/// Return a description telling entries with the same name apart.
+fn describe(entry: &SpotifyEntry) -> String {
+    match entry
+        .owner
+        .as_ref()
+        .and_then(|owner| owner.display_name.as_deref())
+    {
+        Some(owner) => format!("{} by {}", entry.uri, owner),
+        None => entry.uri.clone(),
+    }
+}
@@ -0,0 +1,284 @@
+//! Stateless switch playing specific content or adding it to the queue.
+
+use js_sys::{Function, Promise};
+use std::any::Any;
+use std::cell::RefCell;
+use std::rc::Rc;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::{future_to_promise, spawn_local, JsFuture};
+
+use crate::config::{PresetAction, PresetConfig};
+use crate::logger;
+use crate::node_fetch::error_message;
+use crate::poll_scheduler::PollScheduler;
+use crate::preset_resolver::{self, PresetTarget};
+use crate::spotify_accessory::{create_switch, respond, Accessory, UUIDGen, BUTTON_RESET_DELAY};
+use crate::spotify_api::{PlayRequest, SpotifyApi, SpotifyPlayback};
+use crate::spotify_platform::Service;
+use crate::state_store::StateStore;
+use crate::timer::sleep;
+
+#[derive(Debug)]
+/// Represents the preset content and where it is played, shared with the characteristic handlers.
+struct Preset {
+    /// Name of the switch
+    name: String,
+    /// Content as configured
+    target: PresetTarget,
+    /// Whether the content is played right away or added to the queue
+    action: PresetAction,
+    /// Spotify device name or ID, the active device if not set
+    device: Option<String>,
+    /// URI the content was resolved to, `None` until it is known
+    uri: RefCell<Option<String>>,
+    /// Lookup in progress, awaited by presses until it resolves to the URI or `null`
+    resolving: RefCell<Option<Promise>>,
+    /// API to control Spotify
+    api: Rc<SpotifyApi>,
+    /// Remembers resolved URIs across restarts
+    store: Rc<StateStore>,
+}
+
+#[derive(Debug)]
+/// Represents a preset switch, turns itself off after being pressed.
+pub struct PresetSwitch {
+    /// Name of the switch
+    name: String,
+    /// Accessory to be registered to Homebridge
+    accessory: Accessory,
+    /// Switch service
+    service: Service,
+    /// Characteristic handlers, released when the switch is dropped
+    handlers: Vec<Box<dyn Any>>,
+}
+
+impl PresetSwitch {
+    pub fn new(
+        config: PresetConfig,
+        api: Rc<SpotifyApi>,
+        store: Rc<StateStore>,
+        scheduler: Rc<PollScheduler>,
+    ) -> PresetSwitch {
+        let accessory = Accessory::new(
+            &config.name,
+            &UUIDGen::generate(&format!("preset:{}", config.name)),
+        );
+        let service = create_switch(&config.name);
+        accessory.add_service(&service);
+
+        let mut switch = PresetSwitch {
+            name: config.name.clone(),
+            accessory,
+            service,
+            handlers: Vec::new(),
+        };
+
+        let target = PresetTarget::from_config(&config);
+        let preset = Rc::new(Preset {
+            name: config.name,
+            uri: RefCell::new(target.uri().map(str::to_owned)),
+            resolving: RefCell::new(None),
+            target,
+            action: config.action.unwrap_or(PresetAction::Play),
+            device: config.device,
+            api,
+            store,
+        });
+
+        // look up names right away, so that pressing the switch doesn't need to wait for it
+        if preset.uri.borrow().is_none() {
+            let preset = Rc::clone(&preset);
+            spawn_local(async move {
+                preset.resolve().await;
+            });
+        }
+
+        switch.apply_characteristics(preset, scheduler);
+        switch
+    }
+
+    /// Return the switch name.
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    /// Return the Homebridge accessory.
+    pub fn get_accessory(&self) -> &Accessory {
+        &self.accessory
+    }
+
+    /// Setup up Homebridge characteristics.
+    fn apply_characteristics(&mut self, preset: Rc<Preset>, scheduler: Rc<PollScheduler>) {
+        let get_on = Closure::wrap(Box::new(move |callback: Function| {
+            respond(&callback, Ok(JsValue::from(false)));
+        }) as Box<dyn FnMut(Function)>);
+
+        let service = self.service.clone();
+        let set_on = Closure::wrap(Box::new(move |on: bool, callback: Function| {
+            if !on {
+                respond(&callback, Ok(JsValue::from(false)));
+                return;
+            }
+            scheduler.notify_command();
+
+            let preset = Rc::clone(&preset);
+            let service = service.clone();
+
+            spawn_local(async move {
+                let result = preset.run().await;
+                respond(
+                    &callback,
+                    result
+                        .map(|_| JsValue::from(true))
+                        .map_err(|e| error_message(&e)),
+                );
+
+                // the switch acts like a button
+                sleep(BUTTON_RESET_DELAY).await;
+                service.update_characteristic("On", &JsValue::from(false));
+            });
+        }) as Box<dyn FnMut(bool, Function)>);
+
+        self.service
+            .get_characteristic("On")
+            .on("set", set_on.as_ref().unchecked_ref())
+            .on("get", get_on.as_ref().unchecked_ref());
+
+        self.handlers.push(Box::new(get_on));
+        self.handlers.push(Box::new(set_on));
+    }
+}
+
+impl Preset {
+    /// Return the URI of the content, looking it up if it isn't known yet.
+    /// Presses during a lookup wait for it instead of starting another one.
+    async fn resolve(self: &Rc<Self>) -> Option<String> {
+        if let Some(uri) = self.uri.borrow().clone() {
+            return Some(uri);
+        }
+
+        let resolving = self.resolving.borrow().clone();
+        let promise = match resolving {
+            Some(promise) => promise,
+            None => {
+                let preset = Rc::clone(self);
+                let promise = future_to_promise(async move {
+                    let uri = preset.lookup().await;
+                    preset.resolving.replace(None);
+                    Ok(uri.map_or(JsValue::NULL, JsValue::from))
+                });
+                self.resolving.replace(Some(promise.clone()));
+                promise
+            }
+        };
+
+        JsFuture::from(promise)
+            .await
+            .ok()
+            .and_then(|uri| uri.as_string())
+    }
+
+    /// Look up the URI of the content and remember it, falling back to the URI found before.
+    async fn lookup(&self) -> Option<String> {
+        let key = self.target.cache_key();
+        let uri = match preset_resolver::resolve(&self.api, &self.name, &self.target).await {
+            Ok(Some(uri)) => {
+                self.store.record_preset_uri(&key, &uri);
+                Some(uri)
+            }
+            // e.g. a renamed playlist, keep playing the one found before
+            Ok(None) => {
+                let cached = self.store.preset_uri(&key);
+                if cached.is_some() {
+                    logger::warn(&format!(
+                        "Preset {}: nothing found for {}, using the URI found before",
+                        self.name, key
+                    ));
+                }
+                cached
+            }
+            Err(e) => {
+                let cached = self.store.preset_uri(&key);
+                logger::warn(&format!(
+                    "Preset {}: looking up {} failed: {}{}",
+                    self.name,
+                    key,
+                    error_message(&e),
+                    match cached {
+                        Some(_) => ", using the URI found before",
+                        None => "",
+                    }
+                ));
+                cached
+            }
+        };
+
+        self.uri.replace(uri.clone());
+        uri
+    }
+
+    /// Play or queue the content on the configured device or the device currently playing.
+    async fn run(self: &Rc<Self>) -> Result<(), JsValue> {
+        let uri = match self.resolve().await {
+            Some(uri) => uri,
+            None => {
+                logger::warn(&format!("Preset {}: nothing to play", self.name));
+                return Ok(());
+            }
+        };
+
+        let device_id = match &self.device {
+            Some(device) => self.api.find_device(device).await?.map(|d| d.id),
+            None => {
+                #[allow(deprecated)]
+                let playback: Option<SpotifyPlayback> =
+                    JsFuture::from(self.api.get_playback_state())
+                        .await?
+                        .into_serde()
+                        .unwrap_or(None);
+                playback.map(|p| p.device.id)
+            }
+        };
+
+        let device_id = match device_id {
+            Some(device_id) => device_id,
+            None => {
+                logger::warn(&format!(
+                    "Preset {}: Spotify device {} is not available",
+                    self.name,
+                    self.device.as_deref().unwrap_or("currently playing")
+                ));
+                return Ok(());
+            }
+        };
+
+        match self.action {
+            PresetAction::Play => {
+                self.api
+                    .start_playback(&device_id, &play_request(&uri))
+                    .await?;
+            }
+            PresetAction::Queue => {
+                JsFuture::from(self.api.add_to_queue(device_id, uri)).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Build the request playing a URI, tracks and episodes are played on their own.
+fn play_request(uri: &str) -> PlayRequest {
+    if uri.starts_with("spotify:track:") || uri.starts_with("spotify:episode:") {
+        PlayRequest {
+            uris: Some(vec![uri.to_owned()]),
+            ..PlayRequest::default()
+        }
+    } else {
+        PlayRequest {
+            context_uri: Some(uri.to_owned()),
+            ..PlayRequest::default()
+        }
+    }
+}
@@ -0,0 +1,176 @@
+//! Stateless switches skipping back and forward, seeking within podcast episodes and audiobooks.
+
+use js_sys::Function;
+use std::any::Any;
+use std::rc::Rc;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::{spawn_local, JsFuture};
+
+use crate::config::DeviceConfig;
+use crate::logger;
+use crate::node_fetch::error_message;
+use crate::poll_scheduler::PollScheduler;
+use crate::spotify_accessory::{
+    create_switch_with_subtype, respond, Accessory, BUTTON_RESET_DELAY,
+};
+use crate::spotify_api::{SpotifyApi, SpotifyPlayback};
+use crate::spotify_platform::Service;
+use crate::timer::sleep;
+
+/// Seconds skipped back within an episode if not configured.
+const DEFAULT_SKIP_BACK: u32 = 15;
+/// Seconds skipped forward within an episode if not configured.
+const DEFAULT_SKIP_FORWARD: u32 = 30;
+
+#[derive(Clone, Copy, Debug)]
+/// Direction of a skip.
+enum Direction {
+    Back,
+    Forward,
+}
+
+#[derive(Debug)]
+/// Represents the skip back and skip forward switches of a device.
+pub struct SkipSwitches {
+    /// Characteristic handlers, released when the switches are dropped
+    handlers: Vec<Box<dyn Any>>,
+}
+
+impl SkipSwitches {
+    /// Add the skip switches to the accessory of the device.
+    pub fn new(
+        name: &str,
+        device_id: String,
+        device_config: &DeviceConfig,
+        accessory: &Accessory,
+        api: Rc<SpotifyApi>,
+        scheduler: Rc<PollScheduler>,
+    ) -> SkipSwitches {
+        let mut switches = SkipSwitches {
+            handlers: Vec::new(),
+        };
+
+        let back = device_config.skip_back_seconds.unwrap_or(DEFAULT_SKIP_BACK);
+        let forward = device_config
+            .skip_forward_seconds
+            .unwrap_or(DEFAULT_SKIP_FORWARD);
+
+        for (direction, seconds, label, subtype) in [
+            (Direction::Back, back, "Skip Back", "skip-back"),
+            (Direction::Forward, forward, "Skip Forward", "skip-forward"),
+        ] {
+            let service = create_switch_with_subtype(&format!("{} {}", name, label), subtype);
+            accessory.add_service(&service);
+
+            switches.apply_characteristics(
+                &service,
+                direction,
+                seconds,
+                device_id.clone(),
+                Rc::clone(&api),
+                Rc::clone(&scheduler),
+            );
+        }
+
+        switches
+    }
+
+    /// Setup up Homebridge characteristics of a switch.
+    fn apply_characteristics(
+        &mut self,
+        service: &Service,
+        direction: Direction,
+        seconds: u32,
+        device_id: String,
+        api: Rc<SpotifyApi>,
+        scheduler: Rc<PollScheduler>,
+    ) {
+        let get_on = Closure::wrap(Box::new(move |callback: Function| {
+            respond(&callback, Ok(JsValue::from(false)));
+        }) as Box<dyn FnMut(Function)>);
+
+        let switch = service.clone();
+        let set_on = Closure::wrap(Box::new(move |on: bool, callback: Function| {
+            if !on {
+                respond(&callback, Ok(JsValue::from(false)));
+                return;
+            }
+            scheduler.notify_command();
+
+            let api = Rc::clone(&api);
+            let device_id = device_id.clone();
+            let switch = switch.clone();
+
+            spawn_local(async move {
+                let result = skip(&api, &device_id, direction, seconds).await;
+                respond(
+                    &callback,
+                    result
+                        .map(|_| JsValue::from(true))
+                        .map_err(|e| error_message(&e)),
+                );
+
+                // the switch acts like a button
+                sleep(BUTTON_RESET_DELAY).await;
+                switch.update_characteristic("On", &JsValue::from(false));
+            });
+        }) as Box<dyn FnMut(bool, Function)>);
+
+        service
+            .get_characteristic("On")
+            .on("set", set_on.as_ref().unchecked_ref())
+            .on("get", get_on.as_ref().unchecked_ref());
+
+        self.handlers.push(Box::new(get_on));
+        self.handlers.push(Box::new(set_on));
+    }
+}
+
+/// Seek within an episode or chapter, or skip to the previous or next track otherwise.
+async fn skip(
+    api: &SpotifyApi,
+    device_id: &str,
+    direction: Direction,
+    seconds: u32,
+) -> Result<(), JsValue> {
+    #[allow(deprecated)]
+    let playback: Option<SpotifyPlayback> = JsFuture::from(api.get_playback_state())
+        .await?
+        .into_serde()
+        .unwrap_or(None);
+
+    let playback = match playback {
+        Some(playback) if playback.device.id == device_id => playback,
+        _ => {
+            logger::warn(&format!(
+                "Nothing is playing on Spotify device {}, nothing to skip",
+                device_id
+            ));
+            return Ok(());
+        }
+    };
+
+    let device_id = device_id.to_owned();
+
+    if playback.is_spoken_word() {
+        let progress = playback.progress_ms.unwrap_or(0);
+        let jump = u64::from(seconds) * 1000;
+        let position = match direction {
+            Direction::Back => progress.saturating_sub(jump),
+            // seeking past the end would skip to the next episode
+            Direction::Forward => match playback.item.as_ref().and_then(|i| i.duration_ms()) {
+                Some(duration) => (progress + jump).min(duration.saturating_sub(1000)),
+                None => progress + jump,
+            },
+        };
+        JsFuture::from(api.seek(device_id, position)).await?;
+    } else {
+        match direction {
+            Direction::Back => JsFuture::from(api.previous(device_id)).await?,
+            Direction::Forward => JsFuture::from(api.next(device_id)).await?,
+        };
+    }
+
+    Ok(())
+}
@@ -0,0 +1,353 @@
+//! Sleep timer which fades out and pauses playback when it runs out.
+
+use js_sys::{Date, Function};
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::{spawn_local, JsFuture};
+
+use crate::config::DeviceConfig;
+use crate::logger;
+use crate::node_fetch::error_message;
+use crate::spotify_accessory::{respond, Accessory};
+use crate::spotify_api::SpotifyApi;
+use crate::spotify_platform::Service;
+use crate::spotify_state::SharedState;
+use crate::timer::{sleep, Timeout};
+use crate::volume_ramp::VolumeRamp;
+
+/// Duration of the fade at the end of the timer.
+const DEFAULT_FADE: u32 = 5; // minutes
+const MINUTE: u32 = 60 * 1000; // milliseconds
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(js_name = createSleepTimer)]
+    fn create_sleep_timer(name: &str) -> Service;
+}
+
+#[derive(Debug, Default)]
+/// Represents a running timer.
+struct TimerState {
+    /// Time at which playback gets paused
+    ends_at: Option<f64>,
+    /// Timeout starting the fade
+    timeout: Option<Timeout>,
+    /// Volume to restore once the fade is over
+    fading_from: Option<u32>,
+    /// Incremented whenever the timer gets started or stopped
+    generation: u32,
+}
+
+#[derive(Debug)]
+/// Counts down and pauses the device, shared with the characteristic handlers.
+struct Countdown {
+    /// Lightbulb service, On while running and Brightness as minutes remaining
+    service: Service,
+    /// API to control Spotify
+    api: Rc<SpotifyApi>,
+    /// ID of device to be paused
+    device_id: String,
+    /// Spotify state retrieved during the last platform refresh
+    state: SharedState,
+    /// Volume ramp shared with the device, so that HomeKit commands cancel the fade
+    ramp: Rc<VolumeRamp>,
+    /// Minutes the timer runs when turned on
+    duration: u32,
+    /// Minutes over which the volume is lowered before pausing
+    fade: u32,
+    /// Timer in progress
+    timer: RefCell<TimerState>,
+    /// Characteristic values last pushed to HomeKit
+    reported: Cell<Option<(bool, u32)>>,
+}
+
+#[derive(Debug)]
+/// Represents the sleep timer of a Spotify device.
+pub struct SleepTimer {
+    /// Timer state and behaviour
+    countdown: Rc<Countdown>,
+    /// Characteristic handlers, released when the timer is dropped
+    handlers: Vec<Box<dyn Any>>,
+}
+
+impl SleepTimer {
+    /// Add a sleep timer service to the accessory of the device.
+    pub fn new(
+        name: &str,
+        device_id: String,
+        device_config: &DeviceConfig,
+        accessory: &Accessory,
+        api: Rc<SpotifyApi>,
+        state: SharedState,
+        ramp: Rc<VolumeRamp>,
+    ) -> SleepTimer {
+        let service = create_sleep_timer(&format!("{} Sleep Timer", name));
+        accessory.add_service(&service);
+
+        let mut sleep_timer = SleepTimer {
+            countdown: Rc::new(Countdown {
+                service,
+                api,
+                device_id,
+                state,
+                ramp,
+                duration: device_config.sleep_timer.unwrap_or(30).max(1),
+                fade: device_config.sleep_timer_fade.unwrap_or(DEFAULT_FADE),
+                timer: RefCell::new(TimerState::default()),
+                reported: Cell::new(None),
+            }),
+            handlers: Vec::new(),
+        };
+
+        sleep_timer.apply_characteristics();
+        sleep_timer
+    }
+
+    /// Stop the timer without pausing playback, e.g. when Homebridge shuts down.
+    pub fn stop(&self) {
+        if self
+            .countdown
+            .timer
+            .borrow_mut()
+            .fading_from
+            .take()
+            .is_some()
+        {
+            self.countdown.ramp.cancel();
+        }
+        self.countdown.reset();
+    }
+
+    /// Push the remaining time to HomeKit.
+    pub fn update_characteristics(&self) {
+        self.countdown.push();
+    }
+
+    /// Setup up Homebridge characteristics.
+    fn apply_characteristics(&mut self) {
+        let countdown = Rc::clone(&self.countdown);
+        let get_on = Closure::wrap(Box::new(move |callback: Function| {
+            respond(&callback, Ok(JsValue::from(countdown.values().0)));
+        }) as Box<dyn FnMut(Function)>);
+
+        let countdown = Rc::clone(&self.countdown);
+        let set_on = Closure::wrap(Box::new(move |on: bool, callback: Function| {
+            match (on, countdown.remaining()) {
+                (true, None) => countdown.start(countdown.duration),
+                (false, Some(_)) => countdown.stop(),
+                _ => (),
+            }
+            respond(&callback, Ok(JsValue::from(on)));
+        }) as Box<dyn FnMut(bool, Function)>);
+
+        let countdown = Rc::clone(&self.countdown);
+        let get_minutes = Closure::wrap(Box::new(move |callback: Function| {
+            respond(&callback, Ok(JsValue::from(countdown.values().1)));
+        }) as Box<dyn FnMut(Function)>);
+
+        let countdown = Rc::clone(&self.countdown);
+        let set_minutes = Closure::wrap(Box::new(move |minutes: u32, callback: Function| {
+            if minutes == 0 {
+                countdown.stop();
+            } else {
+                countdown.start(minutes);
+            }
+            respond(&callback, Ok(JsValue::from(minutes)));
+        }) as Box<dyn FnMut(u32, Function)>);
+
+        let service = &self.countdown.service;
+        service
+            .get_characteristic("On")
+            .on("set", set_on.as_ref().unchecked_ref())
+            .on("get", get_on.as_ref().unchecked_ref());
+        service
+            .get_characteristic("Brightness")
+            .on("set", set_minutes.as_ref().unchecked_ref())
+            .on("get", get_minutes.as_ref().unchecked_ref());
+
+        self.handlers.push(Box::new(get_on));
+        self.handlers.push(Box::new(set_on));
+        self.handlers.push(Box::new(get_minutes));
+        self.handlers.push(Box::new(set_minutes));
+    }
+}
+
+impl Countdown {
+    /// Start or restart the timer.
+    fn start(self: &Rc<Self>, minutes: u32) {
+        self.cancel_fade();
+
+        let fade = self.fade.min(minutes);
+        // the timeout is owned by the countdown, a strong reference would keep both alive forever
+        let countdown = Rc::downgrade(self);
+        let mut timer = self.timer.borrow_mut();
+        timer.generation = timer.generation.wrapping_add(1);
+        let generation = timer.generation;
+
+        timer.ends_at = Some(Date::now() + f64::from(minutes * MINUTE));
+        // replacing the timeout cancels the previous one
+        timer.timeout = Some(Timeout::new((minutes - fade) * MINUTE, move || {
+            if let Some(countdown) = countdown.upgrade() {
+                spawn_local(countdown.fade_out(generation, fade * MINUTE));
+            }
+        }));
+        drop(timer);
+
+        logger::info(&format!(
+            "Sleep timer of Spotify device {} set to {} minutes",
+            self.device_id, minutes
+        ));
+        self.push();
+    }
+
+    /// Stop the timer, restoring the volume if it was fading.
+    fn stop(&self) {
+        self.cancel_fade();
+        self.reset();
+
+        logger::info(&format!(
+            "Sleep timer of Spotify device {} stopped",
+            self.device_id
+        ));
+        self.push();
+    }
+
+    /// Cancel a running fade and restore the volume it started from.
+    fn cancel_fade(&self) {
+        if let Some(volume) = self.timer.borrow_mut().fading_from.take() {
+            self.ramp.cancel();
+
+            let api = Rc::clone(&self.api);
+            let device_id = self.device_id.clone();
+            spawn_local(async move {
+                JsFuture::from(api.set_volume(device_id, volume)).await.ok();
+            });
+        }
+    }
+
+    /// Forget the running timer.
+    fn reset(&self) {
+        let mut timer = self.timer.borrow_mut();
+        timer.generation = timer.generation.wrapping_add(1);
+        timer.ends_at = None;
+        timer.timeout = None;
+        timer.fading_from = None;
+    }
+
+    /// Lower the volume, pause playback and restore the volume for the next time.
+    async fn fade_out(self: Rc<Self>, generation: u32, fade: u32) {
+        let (playing, volume) = {
+            let state = self.state.borrow();
+            (
+                state.is_playing(&self.device_id),
+                state.volume(&self.device_id),
+            )
+        };
+
+        if playing {
+            self.timer.borrow_mut().fading_from = volume;
+
+            let completed = match volume {
+                Some(volume) => {
+                    match self
+                        .ramp
+                        .run(&self.api, &self.device_id, volume, 0, fade)
+                        .await
+                    {
+                        Ok(completed) => completed,
+                        Err(e) => {
+                            logger::error(&format!(
+                                "Failed to fade out Spotify device {}: {}",
+                                self.device_id,
+                                error_message(&e)
+                            ));
+                            true
+                        }
+                    }
+                }
+                // not all devices report their volume
+                None => {
+                    sleep(fade).await;
+                    true
+                }
+            };
+
+            let current = self.timer.borrow().generation == generation;
+            if !current {
+                // restarted or stopped from HomeKit
+                return;
+            }
+
+            if completed {
+                let result = async {
+                    JsFuture::from(self.api.pause(self.device_id.clone())).await?;
+                    match volume {
+                        Some(volume) => {
+                            JsFuture::from(self.api.set_volume(self.device_id.clone(), volume))
+                                .await
+                        }
+                        None => Ok(JsValue::UNDEFINED),
+                    }
+                };
+
+                match result.await {
+                    Ok(_) => logger::info(&format!(
+                        "Sleep timer paused Spotify device {}",
+                        self.device_id
+                    )),
+                    Err(e) => logger::error(&format!(
+                        "Sleep timer failed to pause Spotify device {}: {}",
+                        self.device_id,
+                        error_message(&e)
+                    )),
+                }
+            } else {
+                // another command from HomeKit took over, leave the volume alone
+                logger::info(&format!(
+                    "Sleep timer of Spotify device {} cancelled by another command",
+                    self.device_id
+                ));
+            }
+        }
+
+        if self.timer.borrow().generation == generation {
+            self.reset();
+            self.push();
+        }
+    }
+
+    /// Return the minutes until the timer runs out, if it is running.
+    fn remaining(&self) -> Option<u32> {
+        self.timer.borrow().ends_at.map(|ends_at| {
+            ((ends_at - Date::now()) / f64::from(MINUTE))
+                .ceil()
+                .max(1.0) as u32
+        })
+    }
+
+    /// Return whether the timer is running and the minutes shown on the slider.
+    fn values(&self) -> (bool, u32) {
+        match self.remaining() {
+            Some(minutes) => (true, minutes.min(100)),
+            None => (false, self.duration.min(100)),
+        }
+    }
+
+    /// Push characteristics that changed since they were last pushed.
+    fn push(&self) {
+        let values = self.values();
+        let reported = self.reported.replace(Some(values));
+
+        if reported.map(|r| r.0) != Some(values.0) {
+            self.service
+                .update_characteristic("On", &JsValue::from(values.0));
+        }
+        if reported.map(|r| r.1) != Some(values.1) {
+            self.service
+                .update_characteristic("Brightness", &JsValue::from(values.1));
+        }
+    }
+}
@@ -0,0 +1,131 @@
+//! Captures the playback state and restores it later.
+
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::JsFuture;
+
+use crate::logger;
+use crate::spotify_api::{PlayOffset, PlayRequest, SpotifyApi, SpotifyPlayback};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+/// Represents the playback state at a point in time.
+pub struct PlaybackSnapshot {
+    /// Device that was active
+    pub device_id: String,
+    /// Name of the device, for logging
+    pub device_name: String,
+    /// Album, artist or playlist being played
+    pub context_uri: Option<String>,
+    /// Track, episode or chapter being played
+    pub item_uri: Option<String>,
+    /// Position within the track
+    pub position_ms: u64,
+    /// Whether music was playing
+    pub is_playing: bool,
+    /// Whether playback was shuffled
+    pub shuffle_state: bool,
+    /// `off`, `track` or `context`
+    pub repeat_state: String,
+    /// Device volume, if known
+    pub volume: Option<u32>,
+}
+
+impl PlaybackSnapshot {
+    /// Create a snapshot from the playback state, `None` if there's nothing to restore.
+    pub fn from_playback(playback: SpotifyPlayback) -> Option<PlaybackSnapshot> {
+        let context_uri = playback.context.map(|c| c.uri);
+        let item_uri = playback.item.and_then(|item| item.uri().map(str::to_owned));
+
+        if context_uri.is_none() && item_uri.is_none() {
+            return None;
+        }
+
+        Some(PlaybackSnapshot {
+            device_id: playback.device.id,
+            device_name: playback.device.name,
+            context_uri,
+            item_uri,
+            position_ms: playback.progress_ms.unwrap_or(0),
+            is_playing: playback.is_playing,
+            shuffle_state: playback.shuffle_state.unwrap_or(false),
+            repeat_state: playback.repeat_state.unwrap_or_else(|| "off".to_owned()),
+            volume: playback.device.volume_percent,
+        })
+    }
+}
+
+/// Capture the current playback state, `None` if no device is active.
+pub async fn capture(api: &SpotifyApi) -> Result<Option<PlaybackSnapshot>, JsValue> {
+    #[allow(deprecated)]
+    let playback: Option<SpotifyPlayback> = JsFuture::from(api.get_playback_state())
+        .await?
+        .into_serde()
+        .unwrap_or(None);
+
+    Ok(playback.and_then(PlaybackSnapshot::from_playback))
+}
+
+/// Restore the device, context, track, position, shuffle, repeat and volume of a snapshot.
+pub async fn restore(api: &SpotifyApi, snapshot: &PlaybackSnapshot) -> Result<(), JsValue> {
+    let device_id = &snapshot.device_id;
+
+    JsFuture::from(api.transfer_playback(device_id.clone(), false)).await?;
+
+    if let Some(volume) = snapshot.volume {
+        JsFuture::from(api.set_volume(device_id.clone(), volume)).await?;
+    }
+
+    // a track without a context, e.g. started from search results, is played on its own
+    let request = match (&snapshot.context_uri, &snapshot.item_uri) {
+        (Some(context_uri), item_uri) => {
+            // Spotify rejects offsets for artists
+            let offset = item_uri
+                .clone()
+                .filter(|_| supports_offset(context_uri))
+                .map(|uri| PlayOffset::Uri { uri });
+
+            PlayRequest {
+                context_uri: Some(context_uri.clone()),
+                // the position only applies to the captured track
+                position_ms: offset.as_ref().map(|_| snapshot.position_ms),
+                offset,
+                ..PlayRequest::default()
+            }
+        }
+        (None, item_uri) => PlayRequest {
+            uris: Some(item_uri.iter().cloned().collect()),
+            position_ms: item_uri.as_ref().map(|_| snapshot.position_ms),
+            ..PlayRequest::default()
+        },
+    };
+    api.start_playback(device_id, &request).await?;
+
+    // without an offset Spotify picks the track, e.g. for artists, which might still be the captured one
+    if request.position_ms.is_none() && snapshot.item_uri.is_some() {
+        let playback = capture(api).await?;
+
+        if playback.and_then(|p| p.item_uri) == snapshot.item_uri {
+            JsFuture::from(api.seek(device_id.clone(), snapshot.position_ms)).await?;
+        }
+    }
+
+    // changing shuffle before starting the context would pick another track
+    JsFuture::from(api.set_shuffle(device_id.clone(), snapshot.shuffle_state)).await?;
+    JsFuture::from(api.set_repeat(device_id.clone(), snapshot.repeat_state.clone())).await?;
+
+    if !snapshot.is_playing {
+        JsFuture::from(api.pause(device_id.clone())).await?;
+    }
+
+    logger::info(&format!(
+        "Restored playback on Spotify device {}",
+        snapshot.device_name
+    ));
+    Ok(())
+}
+
+/// Return whether playback of a context can start at a specific track.
+fn supports_offset(context_uri: &str) -> bool {
+    ["spotify:album:", "spotify:playlist:", "spotify:show:"]
+        .iter()
+        .any(|prefix| context_uri.starts_with(prefix))
+}
@@ -0,0 +1,150 @@
+//! Switch capturing the playback state when turned on and restoring it when turned off.
+
+use js_sys::Function;
+use std::any::Any;
+use std::rc::Rc;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::{spawn_local, JsFuture};
+
+use crate::config::SnapshotConfig;
+use crate::node_fetch::error_message;
+use crate::poll_scheduler::PollScheduler;
+use crate::snapshot::PlaybackSnapshot;
+use crate::spotify_accessory::{create_switch, respond, Accessory, UUIDGen};
+use crate::spotify_api::SpotifyApi;
+use crate::spotify_platform::Service;
+
+#[derive(Debug)]
+/// Represents a snapshot switch, On while a snapshot is stored.
+pub struct SnapshotSwitch {
+    /// Name of the switch and the snapshot
+    name: String,
+    /// Accessory to be registered to Homebridge
+    accessory: Accessory,
+    /// Switch service
+    service: Service,
+    /// Characteristic handlers, released when the switch is dropped
+    handlers: Vec<Box<dyn Any>>,
+}
+
+impl SnapshotSwitch {
+    pub fn new(
+        config: SnapshotConfig,
+        api: Rc<SpotifyApi>,
+        scheduler: Rc<PollScheduler>,
+    ) -> SnapshotSwitch {
+        let accessory = Accessory::new(
+            &config.name,
+            &UUIDGen::generate(&format!("snapshot:{}", config.name)),
+        );
+        let service = create_switch(&config.name);
+        accessory.add_service(&service);
+
+        let mut switch = SnapshotSwitch {
+            name: config.name.clone(),
+            accessory,
+            service,
+            handlers: Vec::new(),
+        };
+
+        switch.apply_characteristics(config.pause.unwrap_or(true), api, scheduler);
+        switch
+    }
+
+    /// Return the switch name.
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    /// Return the Homebridge accessory.
+    pub fn get_accessory(&self) -> &Accessory {
+        &self.accessory
+    }
+
+    /// Setup up Homebridge characteristics.
+    fn apply_characteristics(
+        &mut self,
+        pause: bool,
+        api: Rc<SpotifyApi>,
+        scheduler: Rc<PollScheduler>,
+    ) {
+        let name = self.name.clone();
+        let get_api = Rc::clone(&api);
+        let get_on = Closure::wrap(Box::new(move |callback: Function| {
+            respond(
+                &callback,
+                Ok(JsValue::from(get_api.has_snapshot(name.clone()))),
+            );
+        }) as Box<dyn FnMut(Function)>);
+
+        let name = self.name.clone();
+        let service = self.service.clone();
+        let set_on = Closure::wrap(Box::new(move |on: bool, callback: Function| {
+            scheduler.notify_command();
+
+            let api = Rc::clone(&api);
+            let name = name.clone();
+            let service = service.clone();
+
+            spawn_local(async move {
+                let result = if on {
+                    Self::save(&api, name, pause).await
+                } else {
+                    Self::restore(&api, name).await
+                };
+                let stored = result.as_ref().ok().copied();
+
+                respond(
+                    &callback,
+                    result
+                        .map(|_| JsValue::from(on))
+                        .map_err(|e| error_message(&e)),
+                );
+
+                // nothing was playing, so there is nothing to restore later
+                if on && stored == Some(false) {
+                    service.update_characteristic("On", &JsValue::from(false));
+                }
+            });
+        }) as Box<dyn FnMut(bool, Function)>);
+
+        self.service
+            .get_characteristic("On")
+            .on("set", set_on.as_ref().unchecked_ref())
+            .on("get", get_on.as_ref().unchecked_ref());
+
+        self.handlers.push(Box::new(get_on));
+        self.handlers.push(Box::new(set_on));
+    }
+
+    /// Capture the playback state and pause it, resolves to whether a snapshot was stored.
+    async fn save(api: &SpotifyApi, name: String, pause: bool) -> Result<bool, JsValue> {
+        #[allow(deprecated)]
+        let snapshot: Option<PlaybackSnapshot> = JsFuture::from(api.save_snapshot(name))
+            .await?
+            .into_serde()
+            .unwrap_or(None);
+
+        match snapshot {
+            Some(snapshot) => {
+                if pause && snapshot.is_playing {
+                    JsFuture::from(api.pause(snapshot.device_id)).await?;
+                }
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Restore the playback state and forget the snapshot.
+    async fn restore(api: &SpotifyApi, name: String) -> Result<bool, JsValue> {
+        if !api.has_snapshot(name.clone()) {
+            return Ok(false);
+        }
+
+        JsFuture::from(api.restore_snapshot(name.clone())).await?;
+        api.delete_snapshot(name);
+        Ok(true)
+    }
+}
@@ -1,40 +1,58 @@
 //! Defines the Homebridge Spotify Accessory.
 
-use crate::spotify_api::SpotifyApi;
+use crate::like_switch::LikeSwitch;
+use crate::poll_scheduler::PollScheduler;
+use crate::skip_switches::SkipSwitches;
+use crate::sleep_timer::SleepTimer;
+use crate::spotify_api::{PlayRequest, SpotifyApi};
+use crate::spotify_state::SharedState;
+use crate::state_store::StateStore;
+use crate::volume_curve::VolumeMapping;
+use crate::volume_debouncer::VolumeDebouncer;
+use crate::volume_ramp::VolumeRamp;
 use js_sys::Array;
+use js_sys::Error;
 use js_sys::Function;
+use std::any::Any;
+use std::cell::Cell;
 use std::rc::Rc;
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::JsCast;
 use wasm_bindgen_futures::spawn_local;
 use wasm_bindgen_futures::JsFuture;
 
-use crate::spotify_platform::{Service, ServiceType};
+use crate::config::{DeviceConfig, ServiceType};
+use crate::logger;
+use crate::node_fetch::{error_message, error_reason, error_status};
+use crate::spotify_platform::Service;
+
+/// Time after which switches acting as buttons turn themselves off again.
+pub const BUTTON_RESET_DELAY: u32 = 1000; // milliseconds
 
 #[wasm_bindgen]
 extern "C" {
     #[wasm_bindgen(method, js_name = getCharacteristic)]
-    fn get_characteristic(this: &Service, name: &str) -> Characteristic;
+    pub fn get_characteristic(this: &Service, name: &str) -> Characteristic;
 
     pub type Characteristic;
 
     #[wasm_bindgen(method)]
-    fn on(this: &Characteristic, event: &str, listener: &Function) -> Characteristic;
+    pub fn on(this: &Characteristic, event: &str, listener: &Function) -> Characteristic;
 
     #[wasm_bindgen(method, js_name = setValue)]
     fn set_value(this: &Characteristic, value: &str);
 
-    #[wasm_bindgen(method, js_name = getValue)]
-    fn get_value(this: &Characteristic);
+    #[wasm_bindgen(method, js_name = updateCharacteristic)]
+    pub fn update_characteristic(this: &Service, name: &str, value: &JsValue) -> Service;
 
     #[derive(Debug, PartialEq)]
     pub type Accessory;
 
     #[wasm_bindgen(constructor, js_class = "Accessory")]
-    fn new(name: &str, uuid: &str) -> Accessory;
+    pub fn new(name: &str, uuid: &str) -> Accessory;
 
     #[wasm_bindgen(method, js_name = addService)]
-    fn add_service(this: &Accessory, service: &Service);
+    pub fn add_service(this: &Accessory, service: &Service);
 
     #[wasm_bindgen(method, getter = UUID)]
     pub fn get_uuid(this: &Accessory) -> String;
@@ -42,13 +60,22 @@ extern "C" {
     pub type UUIDGen;
 
     #[wasm_bindgen(static_method_of = UUIDGen)]
-    fn generate(uuid_base: &str) -> String;
+    pub fn generate(uuid_base: &str) -> String;
 
     #[wasm_bindgen(js_name = createLight)]
     pub fn create_light(name: &str) -> Service;
 
     #[wasm_bindgen(js_name = createSpeaker)]
     pub fn create_speaker(name: &str) -> Service;
+
+    #[wasm_bindgen(js_name = createSwitch)]
+    pub fn create_switch(name: &str) -> Service;
+
+    #[wasm_bindgen(js_name = createSwitch)]
+    pub fn create_switch_with_subtype(name: &str, subtype: &str) -> Service;
+
+    #[wasm_bindgen(js_name = removeServices)]
+    fn remove_services(accessory: &Accessory);
 }
 
 #[wasm_bindgen]
@@ -67,6 +94,52 @@ pub struct SpotifyAccessory {
     accessory: Accessory,
     /// Homebridge service type
     service_type: ServiceType,
+    /// Device specific overrides
+    device_config: DeviceConfig,
+    /// Spotify state retrieved during the last platform refresh
+    state: SharedState,
+    /// Characteristic values last pushed to HomeKit
+    reported: Cell<Option<CharacteristicValues>>,
+    /// Schedules device refreshes
+    scheduler: Rc<PollScheduler>,
+    /// Characteristic handlers, released when the accessory is dropped
+    handlers: Vec<Box<dyn Any>>,
+    /// Coalesces volume changes from HomeKit
+    volume: Rc<VolumeDebouncer>,
+    /// Converts volumes between HomeKit and Spotify
+    volume_mapping: Rc<VolumeMapping>,
+    /// Gradual volume changes, cancelled by any command from HomeKit
+    ramp: Rc<VolumeRamp>,
+    /// Sleep timer service, if enabled for the device
+    sleep_timer: Option<SleepTimer>,
+    /// Skip back and forward switches, if enabled for the device, kept for their handlers
+    _skip_switches: Option<SkipSwitches>,
+    /// Switch saving the current track to the library, if enabled for the device
+    like_switch: Option<LikeSwitch>,
+    /// Device state remembered across restarts
+    store: Rc<StateStore>,
+}
+
+#[derive(Clone, Debug)]
+/// Platform objects shared by all accessories.
+pub struct AccessoryContext {
+    /// API to control Spotify
+    pub api: Rc<SpotifyApi>,
+    /// Spotify state retrieved during the last platform refresh
+    pub state: SharedState,
+    /// Schedules device refreshes
+    pub scheduler: Rc<PollScheduler>,
+    /// Device state remembered across restarts
+    pub store: Rc<StateStore>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+/// Represents the characteristic values of an accessory.
+struct CharacteristicValues {
+    /// Whether the device is playing
+    on: bool,
+    /// Device volume, if known
+    volume: Option<u32>,
 }
 
 impl SpotifyAccessory {
@@ -74,23 +147,103 @@ impl SpotifyAccessory {
         name: String,
         device_id: String,
         service_type: ServiceType,
-        api: Rc<SpotifyApi>,
+        device_config: DeviceConfig,
+        context: AccessoryContext,
+        cached: Option<Accessory>,
     ) -> SpotifyAccessory {
-        // accessory type that can get registered to Homebridge
-        let accessory = Accessory::new(&name, &UUIDGen::generate(&name));
+        let AccessoryContext {
+            api,
+            state,
+            scheduler,
+            store,
+        } = context;
+
+        // the UUID doesn't depend on the configured name, so renaming keeps the accessory
+        let uuid = if device_id.is_empty() {
+            UUIDGen::generate(&name)
+        } else {
+            UUIDGen::generate(&device_id)
+        };
+
+        // device specific settings take precedence over platform settings
+        let name = device_config.name.clone().unwrap_or(name);
+        let service_type = device_config.service_type.clone().unwrap_or(service_type);
+
+        // an accessory restored from the Homebridge cache keeps its UUID, so that HomeKit keeps
+        // its room, scenes and automations, only its services get created again
+        let accessory = match cached {
+            Some(accessory) => {
+                remove_services(&accessory);
+                accessory
+            }
+            // accessory type that can get registered to Homebridge
+            None => Accessory::new(&name, &uuid),
+        };
 
         let service = match service_type {
             ServiceType::Light => create_light(&name),
             ServiceType::Speaker => create_speaker(&name),
         };
 
-        let spotify_accessory = SpotifyAccessory {
+        let volume = Rc::new(VolumeDebouncer::new(api.clone(), device_id.clone()));
+        let volume_mapping = Rc::new(VolumeMapping::new(&device_config));
+        let ramp = Rc::new(VolumeRamp::new());
+
+        let sleep_timer = device_config.sleep_timer.map(|_| {
+            SleepTimer::new(
+                &name,
+                device_id.clone(),
+                &device_config,
+                &accessory,
+                api.clone(),
+                state.clone(),
+                ramp.clone(),
+            )
+        });
+
+        let skip_switches = if device_config.skip_switches.unwrap_or(false) {
+            Some(SkipSwitches::new(
+                &name,
+                device_id.clone(),
+                &device_config,
+                &accessory,
+                api.clone(),
+                scheduler.clone(),
+            ))
+        } else {
+            None
+        };
+
+        let like_switch = device_config.like_switch.map(|mode| {
+            LikeSwitch::new(
+                &name,
+                device_id.clone(),
+                mode,
+                &accessory,
+                api.clone(),
+                state.clone(),
+            )
+        });
+
+        let mut spotify_accessory = SpotifyAccessory {
             service,
             api,
             device_id,
             name,
             accessory,
             service_type,
+            device_config,
+            state,
+            reported: Cell::new(None),
+            scheduler,
+            handlers: Vec::new(),
+            volume,
+            volume_mapping,
+            ramp,
+            sleep_timer,
+            _skip_switches: skip_switches,
+            like_switch,
+            store,
         };
 
         spotify_accessory.apply_characteristics();
@@ -99,26 +252,101 @@ impl SpotifyAccessory {
         spotify_accessory
     }
 
+    /// Stop timers and volume changes of the device, e.g. when Homebridge shuts down.
+    pub fn stop(&self) {
+        self.ramp.cancel();
+        self.volume.stop();
+
+        if let Some(sleep_timer) = &self.sleep_timer {
+            sleep_timer.stop();
+        }
+    }
+
     /// Return the Spotify device ID.
     pub fn get_device_id(&self) -> &str {
         &self.device_id
     }
 
+    /// Return the volume mapping of the device, which applies its volume limits.
+    pub fn get_volume_mapping(&self) -> Rc<VolumeMapping> {
+        Rc::clone(&self.volume_mapping)
+    }
+
+    /// Return the volume ramp of the device, cancelled by any command from HomeKit.
+    pub fn get_ramp(&self) -> Rc<VolumeRamp> {
+        Rc::clone(&self.ramp)
+    }
+
+    /// Return the accessory display name.
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
     /// Return the Homebridge accessory.
     pub fn get_accessory(&self) -> &Accessory {
         &self.accessory
     }
 
-    /// Manually check if the device is playing.
+    /// Push characteristics that changed since the last refresh to HomeKit.
     /// Homekit only checks when the app gets opened, so if the
     /// status changes while the app is open, the status is not
     /// reflected correctly.
-    pub fn check_on(&self) {
-        self.service.get_characteristic("On").get_value();
+    pub fn update_characteristics(&self) {
+        let values = {
+            let state = self.state.borrow();
+            let volume = state.volume(&self.device_id);
+            self.volume.confirm(volume);
+
+            CharacteristicValues {
+                on: state.is_playing(&self.device_id),
+                volume: self
+                    .volume
+                    .optimistic_volume()
+                    .or(volume)
+                    .or_else(|| self.stored_volume())
+                    .map(|v| self.volume_mapping.to_homekit(v)),
+            }
+        };
+        let reported = self.reported.replace(Some(values));
+
+        if reported.map(|r| r.on) != Some(values.on) {
+            match self.service_type {
+                ServiceType::Light => self
+                    .service
+                    .update_characteristic("On", &JsValue::from(values.on)),
+                ServiceType::Speaker => self
+                    .service
+                    .update_characteristic("Mute", &JsValue::from(!values.on)),
+            };
+        }
+
+        if let Some(volume) = values.volume {
+            if reported.and_then(|r| r.volume) != Some(volume) {
+                let name = match self.service_type {
+                    ServiceType::Light => "Brightness",
+                    ServiceType::Speaker => "Volume",
+                };
+                self.service
+                    .update_characteristic(name, &JsValue::from(volume));
+            }
+        }
+
+        if let Some(sleep_timer) = &self.sleep_timer {
+            sleep_timer.update_characteristics();
+        }
+
+        if let Some(like_switch) = &self.like_switch {
+            like_switch.update_characteristics();
+        }
+    }
+
+    /// Return the volume remembered from before the last restart.
+    fn stored_volume(&self) -> Option<u32> {
+        self.store.device(&self.device_id).and_then(|d| d.volume)
     }
 
     /// Setup up Homebridge characteristics.
-    fn apply_characteristics(&self) {
+    fn apply_characteristics(&mut self) {
         let get_on = self.get_on();
         let set_on = self.set_on();
 
@@ -155,10 +383,10 @@ impl SpotifyAccessory {
             .get_characteristic("Name")
             .set_value(&self.name);
 
-        get_on.forget();
-        set_on.forget();
-        set_volume.forget();
-        get_volume.forget();
+        self.handlers.push(Box::new(get_on));
+        self.handlers.push(Box::new(set_on));
+        self.handlers.push(Box::new(get_volume));
+        self.handlers.push(Box::new(set_volume));
     }
 
     /// Connect service with accessory.
@@ -168,26 +396,22 @@ impl SpotifyAccessory {
 
     /// Return closure returning whether Spotify is currently playing or is paused.
     fn get_on(&self) -> Closure<dyn FnMut(Function)> {
-        let api = Rc::clone(&self.api);
+        let state = Rc::clone(&self.state);
         let device_id = self.device_id.clone();
+        let service_type = self.service_type.clone();
 
         Closure::wrap(Box::new(move |callback: Function| {
-            let api = api.clone();
-            let device_id = device_id.clone();
+            let state = state.borrow();
+            let on = state.check_device(&device_id).map(|_| {
+                let playing = state.is_playing(&device_id);
 
-            spawn_local(async move {
-                let on = match JsFuture::from(api.is_playing(device_id)).await {
-                    Ok(state) => state.as_bool().unwrap_or(false),
-                    Err(_) => false,
-                };
-
-                callback
-                    .apply(
-                        &JsValue::null(),
-                        &Array::of2(&JsValue::null(), &JsValue::from(on)),
-                    )
-                    .ok();
+                match service_type {
+                    ServiceType::Light => JsValue::from(playing),
+                    ServiceType::Speaker => JsValue::from(!playing),
+                }
             });
+
+            respond(&callback, on);
         }) as Box<dyn FnMut(Function)>)
     }
 
@@ -196,77 +420,245 @@ impl SpotifyAccessory {
         let api = Rc::clone(&self.api);
         let device_id = self.device_id.clone();
         let service_type = self.service_type.clone();
+        let device_config = self.device_config.clone();
+        let state = Rc::clone(&self.state);
+        let scheduler = Rc::clone(&self.scheduler);
+        let ramp = Rc::clone(&self.ramp);
+        let store = Rc::clone(&self.store);
+        let mapping = Rc::clone(&self.volume_mapping);
 
         Closure::wrap(Box::new(move |new_on: bool, callback: Function| {
-            match service_type {
-                ServiceType::Light => {
-                    if new_on {
-                        let _ = api.play(device_id.clone());
-                    } else {
-                        let _ = api.pause(device_id.clone());
+            if !device_config.allow_play_pause.unwrap_or(true) {
+                // an error makes HomeKit roll the switch back to its previous state
+                let message = format!("Play/pause is disabled for Spotify device {}", device_id);
+                logger::warn(&message);
+                respond(&callback, Err(message));
+                return;
+            }
+
+            scheduler.notify_command();
+            ramp.cancel();
+
+            // speaker uses the Mute characteristic which is basically
+            // the inverse of on/off for play/pause
+            // Mute on == pause
+            // Mute off == play
+            let play = match service_type {
+                ServiceType::Light => new_on,
+                ServiceType::Speaker => !new_on,
+            };
+
+            let api = api.clone();
+            let device_id = device_id.clone();
+            let ramp = ramp.clone();
+            let volume = state
+                .borrow()
+                .volume(&device_id)
+                .or_else(|| store.device(&device_id).and_then(|d| d.volume));
+            // device holding the playback session, if any
+            let session_device = state
+                .borrow()
+                .playback
+                .as_ref()
+                .map(|p| p.device.id.clone());
+            let fallback_context = store
+                .context_uri(&device_id)
+                .or_else(|| device_config.default_context_uri.clone());
+            let fade_in = device_config.fade_in.filter(|&seconds| seconds > 0);
+            let fade_out = device_config.fade_out.filter(|&seconds| seconds > 0);
+            // the configured default volume and a volume changed outside of HomeKit
+            // might exceed the volume cap and limits
+            let default_volume = device_config.default_volume.map(|v| mapping.limit(v));
+            let fade_target = default_volume.or_else(|| volume.map(|v| mapping.limit(v)));
+            let min_volume = device_config.min_volume.unwrap_or(0);
+
+            spawn_local(async move {
+                if play {
+                    let fade = fade_in.zip(fade_target);
+
+                    if fade.is_some() {
+                        // fails if the device isn't active yet, the fade sets the volume again once playing
+                        JsFuture::from(api.set_volume(device_id.clone(), min_volume))
+                            .await
+                            .ok();
                     }
-                }
-                ServiceType::Speaker => {
-                    // speaker uses the Mute characteristic which is basically
-                    // the inverse of on/off for play/pause
-                    // Mute on == pause
-                    // Mute off == play
-                    if new_on {
-                        let _ = api.pause(device_id.clone());
-                    } else {
-                        let _ = api.play(device_id.clone());
+
+                    let playing =
+                        Self::resume(&api, &device_id, session_device, fallback_context).await;
+
+                    let result = match (playing, fade, default_volume) {
+                        (Ok(_), None, Some(volume)) => {
+                            JsFuture::from(api.set_volume(device_id.clone(), volume)).await
+                        }
+                        (playing, _, _) => playing,
+                    };
+                    let started = result.is_ok();
+
+                    respond(
+                        &callback,
+                        result
+                            .map(|_| JsValue::from(new_on))
+                            .map_err(|e| error_message(&e)),
+                    );
+
+                    if let (true, Some((seconds, target))) = (started, fade) {
+                        Self::fade(&api, &device_id, &ramp, min_volume, target, seconds).await;
+                    }
+                } else if let (Some(seconds), Some(volume)) = (fade_out, volume) {
+                    // the fade takes longer than HomeKit waits for a response
+                    respond(&callback, Ok(JsValue::from(new_on)));
+
+                    if !Self::fade(&api, &device_id, &ramp, volume, 0, seconds).await {
+                        // another command took over
+                        return;
+                    }
+
+                    let result = async {
+                        JsFuture::from(api.pause(device_id.clone())).await?;
+                        JsFuture::from(api.set_volume(device_id.clone(), volume)).await
+                    };
+
+                    if let Err(e) = result.await {
+                        logger::error(&format!(
+                            "Failed to pause Spotify device {} after fading out: {}",
+                            device_id,
+                            error_message(&e)
+                        ));
                     }
+                } else {
+                    let result = JsFuture::from(api.pause(device_id)).await;
+
+                    respond(
+                        &callback,
+                        result
+                            .map(|_| JsValue::from(new_on))
+                            .map_err(|e| error_message(&e)),
+                    );
                 }
+            });
+        }) as Box<dyn FnMut(bool, Function)>)
+    }
+
+    /// Resume playback on the device, or move it from the device currently playing.
+    /// If there is nothing to resume, e.g. because Spotify ended the session overnight,
+    /// start the fallback context instead.
+    async fn resume(
+        api: &SpotifyApi,
+        device_id: &str,
+        session_device: Option<String>,
+        fallback_context: Option<String>,
+    ) -> Result<JsValue, JsValue> {
+        match session_device {
+            Some(session_device) if session_device == device_id => {
+                match JsFuture::from(api.play(device_id.to_owned())).await {
+                    Err(e) if fallback_context.is_some() && nothing_to_resume(&e) => (),
+                    result => return result,
+                }
+            }
+            Some(_) => {
+                return JsFuture::from(api.transfer_playback(device_id.to_owned(), true)).await;
             }
+            None => (),
+        }
 
-            callback
-                .apply(
-                    &JsValue::null(),
-                    &Array::of2(&JsValue::null(), &JsValue::from(new_on)),
-                )
-                .ok();
-        }) as Box<dyn FnMut(bool, Function)>)
+        let context_uri = match fallback_context {
+            Some(context_uri) => context_uri,
+            None => return JsFuture::from(api.play(device_id.to_owned())).await,
+        };
+
+        logger::info(&format!(
+            "Nothing to resume on Spotify device {}, starting {}",
+            device_id, context_uri
+        ));
+        let request = PlayRequest {
+            context_uri: Some(context_uri),
+            ..PlayRequest::default()
+        };
+        api.start_playback(device_id, &request).await
+    }
+
+    /// Gradually change the volume of the device.
+    /// Returns `false` if the fade got cancelled by another command.
+    async fn fade(
+        api: &SpotifyApi,
+        device_id: &str,
+        ramp: &VolumeRamp,
+        from: u32,
+        to: u32,
+        seconds: u32,
+    ) -> bool {
+        match ramp.run(api, device_id, from, to, seconds * 1000).await {
+            Ok(completed) => completed,
+            Err(e) => {
+                logger::error(&format!(
+                    "Failed to fade volume of Spotify device {}: {}",
+                    device_id,
+                    error_message(&e)
+                ));
+                true
+            }
+        }
     }
 
     /// Returns closure indicating the current volume.
     fn get_volume(&self) -> Closure<dyn FnMut(Function)> {
-        let api = Rc::clone(&self.api);
+        let state = Rc::clone(&self.state);
         let device_id = self.device_id.clone();
+        let debouncer = Rc::clone(&self.volume);
+        let mapping = Rc::clone(&self.volume_mapping);
+        let store = Rc::clone(&self.store);
 
         Closure::wrap(Box::new(move |callback: Function| {
-            let api = api.clone();
-            let device_id = device_id.clone();
-
-            spawn_local(async move {
-                let volume: u32 = match JsFuture::from(api.get_volume(device_id)).await {
-                    Ok(state) => (state.as_f64().unwrap_or(50.0) as u32),
-                    Err(_) => 50,
-                };
+            // report changes that haven't been applied yet so that the slider doesn't jump back
+            if let Some(volume) = debouncer.optimistic_volume() {
+                respond(&callback, Ok(JsValue::from(mapping.to_homekit(volume))));
+                return;
+            }
 
-                callback
-                    .apply(
-                        &JsValue::null(),
-                        &Array::of2(&JsValue::null(), &JsValue::from(volume)),
-                    )
-                    .ok();
+            let state = state.borrow();
+            let volume = state.check_device(&device_id).map(|_| {
+                // not all devices report their volume, fall back to the one remembered
+                let volume = state
+                    .volume(&device_id)
+                    .or_else(|| store.device(&device_id).and_then(|d| d.volume))
+                    .map(|v| mapping.to_homekit(v));
+                JsValue::from(volume.unwrap_or(50))
             });
+
+            respond(&callback, volume);
         }) as Box<dyn FnMut(Function)>)
     }
 
     /// Closure for setting the volume.
     fn set_volume(&self) -> Closure<dyn FnMut(u32, Function)> {
-        let api = Rc::clone(&self.api);
-        let device_id = self.device_id.clone();
+        let debouncer = Rc::clone(&self.volume);
+        let mapping = Rc::clone(&self.volume_mapping);
+        let scheduler = Rc::clone(&self.scheduler);
+        let ramp = Rc::clone(&self.ramp);
 
         Closure::wrap(Box::new(move |new_volume: u32, callback: Function| {
-            let _ = api.set_volume(device_id.clone(), new_volume);
-
-            callback
-                .apply(
-                    &JsValue::null(),
-                    &Array::of2(&JsValue::null(), &JsValue::from(new_volume)),
-                )
-                .ok();
+            scheduler.notify_command();
+            // the volume set from HomeKit takes precedence over a running fade
+            ramp.cancel();
+
+            // applies the volume curve and limits
+            debouncer.set_volume(mapping.to_spotify(new_volume), callback);
         }) as Box<dyn FnMut(u32, Function)>)
     }
 }
+
+/// Check if starting playback failed because there is no session to resume.
+fn nothing_to_resume(error: &JsValue) -> bool {
+    error_reason(error).as_deref() == Some("NO_ACTIVE_DEVICE") || error_status(error) == Some(404)
+}
+
+/// Pass the result of a characteristic request to the HomeKit callback.
+/// Errors are reported as communication failure, which shows "No Response" in the Home app.
+pub fn respond(callback: &Function, result: Result<JsValue, String>) {
+    let arguments = match result {
+        Ok(value) => Array::of2(&JsValue::null(), &value),
+        Err(message) => Array::of1(&Error::new(&message)),
+    };
+
+    callback.apply(&JsValue::null(), &arguments).ok();
+}
@@ -1,17 +1,40 @@
 //! Represent the Spotify API.
 
-use crate::node_fetch::{fetch, FetchMethod};
+use crate::ducking::{self, Ducking};
+use crate::logger;
+use crate::node_fetch::{error_message, error_retry_after, error_status, fetch, FetchMethod};
+use crate::snapshot;
+use crate::state_store::StateStore;
+use crate::timer::sleep;
 use base64::encode;
-use js_sys::{Date, Promise};
+use js_sys::{Date, Function, Promise, JSON};
+use serde::de::{Deserialize, DeserializeOwned, Deserializer};
+use serde_json::Value;
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
 use std::rc::Rc;
 use wasm_bindgen::prelude::*;
 use wasm_bindgen_futures::future_to_promise;
 use wasm_bindgen_futures::JsFuture;
-use web_sys::console;
 
 /// The Spotify API access token needs to be refreshed after 50 minutes.
-const ACCESS_TOKEN_LIFETIME: f64 = 3000.0;
+const ACCESS_TOKEN_LIFETIME: f64 = 50.0 * 60.0 * 1000.0; // milliseconds
+/// Base URL of the Spotify Web API.
+const API_URL: &str = "https://api.spotify.com/v1";
+/// Item types the playback state is requested for, episodes include audiobook chapters.
+const ITEM_TYPES: &str = "track,episode";
+/// Item types that can be searched for.
+const SEARCH_TYPES: &[&str] = &["track", "album", "artist", "playlist", "show"];
+/// Number of search results per type if no limit is provided.
+const DEFAULT_SEARCH_LIMIT: usize = 20;
+/// Largest page size supported by the Spotify Web API.
+const PAGE_SIZE: usize = 50;
+/// Number of times a rate limited request is retried.
+const MAX_RETRIES: u32 = 3;
+/// Seconds to wait after being rate limited if Spotify doesn't say how long.
+const DEFAULT_RETRY_AFTER: u32 = 1;
+/// Longest wait in seconds before retrying, requests fail instead of waiting longer.
+const MAX_RETRY_AFTER: u32 = 30;
 /// Path to the Homebridge config file.
 const HOMEBRIDGE_CONFIG: &str = "~/.homebridge/config.json"; // todo: tilde not supported
 
@@ -42,281 +65,780 @@ struct SpotifyAuthorization {
 pub struct SpotifyDevice {
     pub id: String,
     pub is_active: bool,
-    pub volume_percent: u32,
+    pub volume_percent: Option<u32>,
     pub name: String,
     // ... more attributes ...
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug)]
 /// Represents the response when requesting the playback state.
-struct SpotifyPlayback {
+pub struct SpotifyPlayback {
     pub device: SpotifyDevice,
     pub is_playing: bool,
+    pub progress_ms: Option<u64>,
+    pub shuffle_state: Option<bool>,
+    /// `off`, `track` or `context`
+    pub repeat_state: Option<String>,
+    /// Album, artist or playlist being played, if any
+    pub context: Option<SpotifyContext>,
+    /// Track, episode or chapter being played
+    #[serde(default, deserialize_with = "deserialize_item")]
+    pub item: Option<SpotifyItem>,
+    /// `track`, `episode`, `ad` or `unknown`
+    pub currently_playing_type: Option<String>,
     // ... more attributes ...
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+/// Represents the album, artist or playlist being played.
+pub struct SpotifyContext {
+    pub uri: String,
+    // ... more attributes ...
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type")]
+/// Represents the track, podcast episode or audiobook chapter being played.
+pub enum SpotifyItem {
+    #[serde(rename = "track")]
+    Track(SpotifyTrack),
+    #[serde(rename = "episode")]
+    Episode(SpotifyEpisode),
+    #[serde(rename = "chapter")]
+    Chapter(SpotifyEpisode),
+    /// Items the plugin doesn't know about, e.g. ads
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+/// Represents a music track.
+pub struct SpotifyTrack {
+    pub uri: String,
+    pub name: String,
+    pub duration_ms: u64,
+    #[serde(default)]
+    pub artists: Vec<SpotifyArtist>,
+    // ... more attributes ...
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+/// Represents an artist of a track.
+pub struct SpotifyArtist {
+    pub name: String,
+    // ... more attributes ...
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+/// Represents a podcast episode or an audiobook chapter.
+pub struct SpotifyEpisode {
+    pub uri: String,
+    pub name: String,
+    pub duration_ms: u64,
+    /// Podcast the episode belongs to
+    pub show: Option<SpotifyShow>,
+    /// Audiobook the chapter belongs to
+    pub audiobook: Option<SpotifyShow>,
+    // ... more attributes ...
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+/// Represents a podcast or an audiobook.
+pub struct SpotifyShow {
+    pub name: String,
+    // ... more attributes ...
+}
+
+impl SpotifyItem {
+    /// Return the Spotify URI of the item.
+    pub fn uri(&self) -> Option<&str> {
+        match self {
+            SpotifyItem::Track(track) => Some(&track.uri),
+            SpotifyItem::Episode(episode) | SpotifyItem::Chapter(episode) => Some(&episode.uri),
+            SpotifyItem::Other => None,
+        }
+    }
+
+    /// Return the Spotify URI of the item if it can be saved to the library.
+    pub fn saveable_uri(&self) -> Option<&str> {
+        match self {
+            SpotifyItem::Track(track) => Some(&track.uri),
+            SpotifyItem::Episode(episode) => Some(&episode.uri),
+            SpotifyItem::Chapter(_) | SpotifyItem::Other => None,
+        }
+    }
+
+    /// Return the length of the item.
+    pub fn duration_ms(&self) -> Option<u64> {
+        match self {
+            SpotifyItem::Track(track) => Some(track.duration_ms),
+            SpotifyItem::Episode(episode) | SpotifyItem::Chapter(episode) => {
+                Some(episode.duration_ms)
+            }
+            SpotifyItem::Other => None,
+        }
+    }
+
+    /// Return a description for logging, e.g. `Artist - Title` or `Podcast - Episode`.
+    pub fn description(&self) -> String {
+        match self {
+            SpotifyItem::Track(track) => match track.artists.first() {
+                Some(artist) => format!("{} - {}", artist.name, track.name),
+                None => track.name.clone(),
+            },
+            SpotifyItem::Episode(episode) | SpotifyItem::Chapter(episode) => {
+                match episode.show.as_ref().or(episode.audiobook.as_ref()) {
+                    Some(show) => format!("{} - {}", show.name, episode.name),
+                    None => episode.name.clone(),
+                }
+            }
+            SpotifyItem::Other => "unknown item".to_owned(),
+        }
+    }
+}
+
+/// Deserialize the item being played. Items that don't have the expected shape, e.g. local files
+/// without some of the fields, are unknown items instead of failing the whole playback state.
+fn deserialize_item<'de, D>(deserializer: D) -> Result<Option<SpotifyItem>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let item = Option::<Value>::deserialize(deserializer)?;
+
+    Ok(item.map(|item| {
+        SpotifyItem::deserialize(item).unwrap_or_else(|e| {
+            logger::debug(&format!("Unexpected item in playback state: {}", e));
+            SpotifyItem::Other
+        })
+    }))
+}
+
+impl SpotifyPlayback {
+    /// Return whether a podcast episode or an audiobook chapter is being played.
+    pub fn is_spoken_word(&self) -> bool {
+        match &self.item {
+            Some(SpotifyItem::Episode(_)) | Some(SpotifyItem::Chapter(_)) => true,
+            Some(_) => false,
+            None => self.currently_playing_type.as_deref() == Some("episode"),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 /// Represents a list of available Spotify devices
 pub struct SpotifyDevices {
     pub devices: Vec<SpotifyDevice>,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+/// Represents the body of a request to start playback.
+pub struct PlayRequest {
+    /// Spotify URI of the album, artist or playlist to play
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context_uri: Option<String>,
+    /// Spotify URIs of the tracks to play instead of a context
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uris: Option<Vec<String>>,
+    /// Track of the context or track list to start with
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offset: Option<PlayOffset>,
+    /// Position within the track to start at
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub position_ms: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+/// Represents the track of a context or track list playback starts with.
+pub enum PlayOffset {
+    /// Zero-based index of the track
+    Position { position: u32 },
+    /// Spotify URI of the track
+    Uri { uri: String },
+}
+
+impl PlayRequest {
+    /// Check that the request can be understood by Spotify.
+    fn validate(&self) -> Result<(), String> {
+        if self.context_uri.is_some() && self.uris.is_some() {
+            return Err("either context_uri or uris can be provided, not both".to_owned());
+        }
+        if self.uris.as_ref().is_some_and(|uris| uris.is_empty()) {
+            return Err("uris must not be empty".to_owned());
+        }
+        if self.offset.is_some() && self.context_uri.is_none() && self.uris.is_none() {
+            return Err("offset requires context_uri or uris".to_owned());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+/// Represents an album, artist, playlist, podcast or track found by searching or in the library.
+pub struct SpotifyEntry {
+    /// `album`, `artist`, `playlist`, `show` or `track`
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub uri: String,
+    pub name: String,
+    /// Artists of albums and tracks
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub artists: Vec<SpotifyArtist>,
+    /// Owner of playlists
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub owner: Option<SpotifyOwner>,
+    /// Publisher of podcasts
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub publisher: Option<String>,
+    // ... more attributes ...
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+/// Represents the user owning a playlist.
+pub struct SpotifyOwner {
+    pub display_name: Option<String>,
+    // ... more attributes ...
+}
+
+#[derive(Deserialize, Debug)]
+/// Represents a page of an offset or cursor based list returned by the Spotify Web API.
+struct Page<T> {
+    /// Items of the page, search results may contain `null` items
+    items: Vec<Option<T>>,
+    /// URL of the next page, `None` on the last page
+    next: Option<String>,
+    /// Cursors of cursor based lists
+    cursors: Option<Cursors>,
+}
+
+#[derive(Deserialize, Debug)]
+/// Represents the position of a cursor based page.
+struct Cursors {
+    /// Cursor of the last item of the page
+    after: Option<String>,
+}
+
+impl<T> Page<T> {
+    /// Return the URL of the page after the page requested from `url`, if any.
+    fn next_url(&self, url: &str) -> Option<String> {
+        if self.next.is_some() {
+            return self.next.clone();
+        }
+
+        // cursor based lists don't always provide a link to the next page
+        let after = self.cursors.as_ref()?.after.as_deref()?;
+        if self.items.is_empty() {
+            return None;
+        }
+        Some(with_query_param(url, "after", after))
+    }
+}
+
+#[derive(Deserialize, Debug)]
+/// Represents an album saved in the library.
+struct SavedAlbum {
+    album: SpotifyEntry,
+}
+
+#[derive(Deserialize, Debug)]
+/// Represents the response when requesting followed artists.
+struct FollowedArtists {
+    artists: Page<SpotifyEntry>,
+}
+
+#[derive(Serialize, Debug)]
+/// Represents the body of a request to transfer playback to another device.
+struct TransferRequest {
+    /// Device to transfer playback to, Spotify supports only one
+    device_ids: Vec<String>,
+    /// Whether playback starts on the new device
+    play: bool,
+}
+
 #[wasm_bindgen]
 #[derive(Clone, Debug)]
 /// Represents the Spotify API and state.
 pub struct SpotifyApi {
     client_id: String,
     client_secret: String,
-    access_token: Rc<String>,
-    refresh_token: Rc<String>,
-    access_token_timestamp: Rc<f64>,
+    access_token: Rc<RefCell<String>>,
+    refresh_token: Rc<RefCell<String>>,
+    access_token_timestamp: Rc<Cell<f64>>,
+    ducking: Rc<RefCell<Ducking>>,
+    /// Keeps snapshots and ducked playback across restarts
+    store: Rc<StateStore>,
 }
 
 #[wasm_bindgen]
 impl SpotifyApi {
     #[wasm_bindgen(constructor)]
     pub fn new(client_id: String, client_secret: String, refresh_token: String) -> SpotifyApi {
-        SpotifyApi {
-            client_id,
-            client_secret,
-            access_token: Rc::new("".to_owned()),
-            refresh_token: Rc::new(refresh_token),
-            access_token_timestamp: Rc::new(0.0),
-        }
+        // without the platform nothing is remembered across restarts
+        let store = Rc::new(StateStore::load(None));
+        SpotifyApi::with_store(client_id, client_secret, refresh_token, store)
     }
 
     /// Make a request to start playing music.
     pub fn play(&self, device_id: String) -> Promise {
-        let authorize_request = self.authorize();
+        let api = self.clone();
 
         future_to_promise(async move {
-            match JsFuture::from(authorize_request).await {
-                Ok(authorize_request) => {
-                    let access_token: String = authorize_request
-                        .as_string()
-                        .expect("Error authenticating to Spotify API");
+            let url = format!("{}/me/player/play?device_id={}", API_URL, device_id);
 
-                    let mut url = "https://api.spotify.com/v1/me/player/play".to_owned();
-                    url.push_str(&format!("?device_id={}", device_id));
+            api.request(FetchMethod::Put, &url, "", true)
+                .await
+                .map_err(|e| log_error("Error starting playback", e))
+        })
+    }
 
-                    let authorization_header = format!("Bearer {}", access_token);
+    /// Start specific content on a device. `content` is an object with the optional fields
+    /// `context_uri`, `uris`, `offset` (`{ position }` or `{ uri }`) and `position_ms`,
+    /// without any of them playback gets resumed.
+    pub fn play_content(&self, device_id: String, content: JsValue) -> Promise {
+        let api = self.clone();
 
-                    let mut headers = HashMap::new();
-                    headers.insert("Authorization".to_owned(), authorization_header);
+        future_to_promise(async move {
+            #[allow(deprecated)]
+            let request: PlayRequest = if content.is_undefined() || content.is_null() {
+                PlayRequest::default()
+            } else {
+                content
+                    .into_serde()
+                    .map_err(|e| log_error("Invalid content to play", e.to_string()))?
+            };
 
-                    match fetch(&url, FetchMethod::Put, "", headers, true).await {
-                        Err(e) => {
-                            console::log_1(&format!("Error starting playback: {:?}", e).into())
-                        }
-                        Ok(_) => {} // player successfully started
-                    }
+            api.start_playback(&device_id, &request).await
+        })
+    }
+
+    /// Start playing an album, artist or playlist on a device.
+    pub fn play_context(&self, device_id: String, context_uri: String) -> Promise {
+        let api = self.clone();
+
+        future_to_promise(async move {
+            let request = PlayRequest {
+                context_uri: Some(context_uri),
+                ..PlayRequest::default()
+            };
+
+            api.start_playback(&device_id, &request).await
+        })
+    }
+
+    /// Transfer playback to a device, `play` starts playback on it.
+    pub fn transfer_playback(&self, device_id: String, play: bool) -> Promise {
+        let api = self.clone();
+
+        future_to_promise(async move {
+            let url = format!("{}/me/player", API_URL);
+            let body = TransferRequest {
+                device_ids: vec![device_id],
+                play,
+            };
+            let body = serde_json::to_string(&body).unwrap_or_default();
+
+            api.request(FetchMethod::Put, &url, &body, true)
+                .await
+                .map_err(|e| log_error("Error transferring playback", e))
+        })
+    }
+
+    /// Turn shuffle on or off for a device.
+    pub fn set_shuffle(&self, device_id: String, shuffle: bool) -> Promise {
+        let api = self.clone();
+
+        future_to_promise(async move {
+            let url = format!(
+                "{}/me/player/shuffle?state={}&device_id={}",
+                API_URL, shuffle, device_id
+            );
+
+            api.request(FetchMethod::Put, &url, "", true)
+                .await
+                .map_err(|e| log_error("Error changing shuffle", e))
+        })
+    }
+
+    /// Set the repeat mode of a device: `off`, `track` or `context`.
+    pub fn set_repeat(&self, device_id: String, state: String) -> Promise {
+        let api = self.clone();
+
+        future_to_promise(async move {
+            let url = format!(
+                "{}/me/player/repeat?state={}&device_id={}",
+                API_URL, state, device_id
+            );
+
+            api.request(FetchMethod::Put, &url, "", true)
+                .await
+                .map_err(|e| log_error("Error changing repeat mode", e))
+        })
+    }
+
+    /// Jump to a position within the track or episode being played.
+    pub fn seek(&self, device_id: String, position_ms: u64) -> Promise {
+        let api = self.clone();
+
+        future_to_promise(async move {
+            let url = format!(
+                "{}/me/player/seek?position_ms={}&device_id={}",
+                API_URL, position_ms, device_id
+            );
+
+            api.request(FetchMethod::Put, &url, "", true)
+                .await
+                .map_err(|e| log_error("Error seeking", e))
+        })
+    }
+
+    /// Skip to the next track.
+    pub fn next(&self, device_id: String) -> Promise {
+        let api = self.clone();
+
+        future_to_promise(async move {
+            let url = format!("{}/me/player/next?device_id={}", API_URL, device_id);
+
+            api.request(FetchMethod::Post, &url, "", true)
+                .await
+                .map_err(|e| log_error("Error skipping to the next track", e))
+        })
+    }
+
+    /// Skip to the previous track.
+    pub fn previous(&self, device_id: String) -> Promise {
+        let api = self.clone();
+
+        future_to_promise(async move {
+            let url = format!("{}/me/player/previous?device_id={}", API_URL, device_id);
+
+            api.request(FetchMethod::Post, &url, "", true)
+                .await
+                .map_err(|e| log_error("Error skipping to the previous track", e))
+        })
+    }
+
+    /// Add a track or episode to the end of the queue of a device.
+    pub fn add_to_queue(&self, device_id: String, uri: String) -> Promise {
+        let api = self.clone();
+
+        future_to_promise(async move {
+            let url = format!(
+                "{}/me/player/queue?uri={}&device_id={}",
+                API_URL,
+                encode_component(&uri),
+                device_id
+            );
+
+            api.request(FetchMethod::Post, &url, "", true)
+                .await
+                .map_err(|e| log_error("Error adding to the queue", e))
+        })
+    }
+
+    /// Get the item being played and the items queued after it.
+    /// Resolves to `{ currently_playing, queue }`.
+    pub fn get_queue(&self) -> Promise {
+        let api = self.clone();
+
+        future_to_promise(async move {
+            let url = format!("{}/me/player/queue", API_URL);
+
+            api.request(FetchMethod::Get, &url, "", false)
+                .await
+                .map_err(|e| log_error("Error fetching the queue", e))
+        })
+    }
+
+    /// Check whether the track or episode being played is saved in the library.
+    /// Resolves to `null` if nothing is playing that can be saved.
+    pub fn is_current_item_saved(&self) -> Promise {
+        let api = self.clone();
+
+        future_to_promise(async move {
+            match api.current_item_uri().await? {
+                Some(uri) => Ok(JsValue::from(api.is_saved(&uri).await?)),
+                None => Ok(JsValue::NULL),
+            }
+        })
+    }
+
+    /// Save the track or episode being played to the library.
+    /// Resolves to its URI, or `null` if nothing is playing that can be saved.
+    pub fn save_current_item(&self) -> Promise {
+        let api = self.clone();
+
+        future_to_promise(async move {
+            match api.current_item_uri().await? {
+                Some(uri) => {
+                    api.set_saved(&uri, true).await?;
+                    Ok(JsValue::from(uri))
+                }
+                None => Ok(JsValue::NULL),
+            }
+        })
+    }
+
+    /// Remove the track or episode being played from the library.
+    /// Resolves to its URI, or `null` if nothing is playing that can be saved.
+    pub fn remove_current_item(&self) -> Promise {
+        let api = self.clone();
+
+        future_to_promise(async move {
+            match api.current_item_uri().await? {
+                Some(uri) => {
+                    api.set_saved(&uri, false).await?;
+                    Ok(JsValue::from(uri))
                 }
-                Err(e) => console::log_1(
-                    &format!("Error while authenticating to Spotify API: {:?}", e).into(),
-                ),
+                None => Ok(JsValue::NULL),
             }
+        })
+    }
+
+    /// Search for items of the comma-separated `types`: `track`, `album`, `artist`, `playlist` or `show`.
+    /// Resolves to the results keyed by type, e.g. `{ playlists: [...] }`, with up to `limit` results per type.
+    #[allow(deprecated)]
+    pub fn search(&self, query: String, types: String, limit: Option<usize>) -> Promise {
+        let api = self.clone();
+
+        future_to_promise(async move {
+            let types: Vec<&str> = types.split(',').map(str::trim).collect();
+            let results = api
+                .search_items(&query, &types, limit.unwrap_or(DEFAULT_SEARCH_LIMIT))
+                .await?;
+
+            JsValue::from_serde(&results)
+                .map_err(|e| log_error("Error converting search results", e.to_string()))
+        })
+    }
+
+    /// Get the playlists owned or followed by the user, up to `limit` if provided.
+    #[allow(deprecated)]
+    pub fn get_playlists(&self, limit: Option<usize>) -> Promise {
+        let api = self.clone();
+
+        future_to_promise(async move {
+            let playlists = api.playlists(limit).await?;
+
+            JsValue::from_serde(&playlists)
+                .map_err(|e| log_error("Error converting playlists", e.to_string()))
+        })
+    }
+
+    /// Get the albums saved in the library, up to `limit` if provided.
+    #[allow(deprecated)]
+    pub fn get_saved_albums(&self, limit: Option<usize>) -> Promise {
+        let api = self.clone();
+
+        future_to_promise(async move {
+            let albums = api.saved_albums(limit).await?;
 
-            Err(JsValue::from("Error starting playback"))
+            JsValue::from_serde(&albums)
+                .map_err(|e| log_error("Error converting saved albums", e.to_string()))
+        })
+    }
+
+    /// Get the artists followed by the user, up to `limit` if provided.
+    #[allow(deprecated)]
+    pub fn get_followed_artists(&self, limit: Option<usize>) -> Promise {
+        let api = self.clone();
+
+        future_to_promise(async move {
+            let artists = api.followed_artists(limit).await?;
+
+            JsValue::from_serde(&artists)
+                .map_err(|e| log_error("Error converting followed artists", e.to_string()))
         })
     }
 
     /// Make a request to pause Spotify.
     pub fn pause(&self, device_id: String) -> Promise {
-        let authorize_request = self.authorize();
+        let api = self.clone();
 
         future_to_promise(async move {
-            match JsFuture::from(authorize_request).await {
-                Ok(authorize_request) => {
-                    let access_token: String = authorize_request
-                        .as_string()
-                        .expect("Error authenticating to Spotify API");
-
-                    let mut url = "https://api.spotify.com/v1/me/player/pause".to_string();
-                    url.push_str(&format!("?device_id={}", device_id));
-                    let authorization_header = format!("Bearer {}", access_token);
-
-                    let mut headers = HashMap::new();
-                    headers.insert("Authorization".to_owned(), authorization_header);
-
-                    match fetch(&url, FetchMethod::Put, "", headers, true).await {
-                        Err(e) => {
-                            console::log_1(&format!("Error stopping playback: {:?}", e).into())
-                        }
-                        Ok(_) => {} // player successfully stopped playing
-                    }
-                }
-                Err(e) => console::log_1(
-                    &format!("Error while authenticating to Spotify API: {:?}", e).into(),
-                ),
-            }
+            let url = format!("{}/me/player/pause?device_id={}", API_URL, device_id);
 
-            Err(JsValue::from("Error pausing playback"))
+            api.request(FetchMethod::Put, &url, "", true)
+                .await
+                .map_err(|e| log_error("Error pausing playback", e))
         })
     }
 
     /// Check if Spotify device is currently playing.
+    #[allow(deprecated)]
     pub fn is_playing(&self, device_id: String) -> Promise {
-        let authorize_request = self.authorize();
+        let api = self.clone();
 
         future_to_promise(async move {
-            match JsFuture::from(authorize_request).await {
-                Ok(authorize_request) => {
-                    let access_token: String = authorize_request
-                        .as_string()
-                        .expect("Error authenticating to Spotify API");
-
-                    let mut url = "https://api.spotify.com/v1/me/player".to_string();
-                    url.push_str(&format!("?device_id={}", device_id));
-
-                    let authorization_header = format!("Bearer {}", access_token);
-
-                    let mut headers = HashMap::new();
-                    headers.insert("Authorization".to_owned(), authorization_header);
-
-                    match fetch(&url, FetchMethod::Get, "", headers, false).await {
-                        Ok(result) => {
-                            let json_response: Result<SpotifyPlayback, _> = result.into_serde();
-
-                            match json_response {
-                                Ok(json) => {
-                                    return Ok(JsValue::from(
-                                        json.is_playing && json.device.id == device_id,
-                                    ))
-                                }
-                                Err(_) => {
-                                    return Err(JsValue::from("Error fetching playback data"))
-                                }
-                            }
-                        }
-                        _ => {}
-                    }
-                }
-                Err(e) => console::log_1(
-                    &format!("Error while authenticating to Spotify API: {:?}", e).into(),
-                ),
-            }
+            let url = format!(
+                "{}/me/player?device_id={}&additional_types={}",
+                API_URL, device_id, ITEM_TYPES
+            );
+
+            let result = api
+                .request(FetchMethod::Get, &url, "", false)
+                .await
+                .map_err(|e| log_error("Error fetching play state", e))?;
 
-            Err(JsValue::from("Error fetching play state"))
+            match result.into_serde::<Option<SpotifyPlayback>>() {
+                Ok(playback) => Ok(JsValue::from(
+                    playback.is_some_and(|p| p.is_playing && p.device.id == device_id),
+                )),
+                Err(e) => Err(log_error("Error parsing playback state", e.to_string())),
+            }
         })
     }
 
     /// Get volume for a specific device.
+    #[allow(deprecated)]
     pub fn get_volume(&self, device_id: String) -> Promise {
-        let authorize_request = self.authorize();
+        let api = self.clone();
 
         future_to_promise(async move {
-            match JsFuture::from(authorize_request).await {
-                Ok(authorize_request) => {
-                    let access_token: String = authorize_request
-                        .as_string()
-                        .expect("Error authenticating to Spotify API");
-
-                    let mut url = "https://api.spotify.com/v1/me/player".to_string();
-                    url.push_str(&format!("?device_id={}", device_id));
-
-                    let authorization_header = format!("Bearer {}", access_token);
+            let url = format!(
+                "{}/me/player?device_id={}&additional_types={}",
+                API_URL, device_id, ITEM_TYPES
+            );
 
-                    let mut headers = HashMap::new();
-                    headers.insert("Authorization".to_owned(), authorization_header);
+            let result = api
+                .request(FetchMethod::Get, &url, "", false)
+                .await
+                .map_err(|e| log_error("Error fetching volume", e))?;
 
-                    match fetch(&url, FetchMethod::Get, "", headers, false).await {
-                        Err(e) => {
-                            console::log_1(&format!("Error getting volume state: {:?}", e).into())
-                        }
-                        Ok(result) => {
-                            let json_response: Result<SpotifyPlayback, _> = result.into_serde();
-
-                            match json_response {
-                                Ok(json) => return Ok(JsValue::from(json.device.volume_percent)),
-                                Err(_) => {
-                                    return Err(JsValue::from("Error fetching playback data"))
-                                }
-                            }
-                        }
-                    }
-                }
-                Err(e) => console::log_1(
-                    &format!("Error while authenticating to Spotify API: {:?}", e).into(),
-                ),
+            match result.into_serde::<SpotifyPlayback>() {
+                Ok(playback) => Ok(JsValue::from(playback.device.volume_percent)),
+                Err(e) => Err(log_error("Error parsing playback state", e.to_string())),
             }
-
-            Err(JsValue::from("Error fetching volume"))
         })
     }
 
     /// Set the volume for a specific device.
     pub fn set_volume(&self, device_id: String, volume: u32) -> Promise {
-        let authorize_request = self.authorize();
+        let api = self.clone();
 
         future_to_promise(async move {
-            match JsFuture::from(authorize_request).await {
-                Ok(authorize_request) => {
-                    let access_token: String = authorize_request
-                        .as_string()
-                        .expect("Error authenticating to Spotify API");
-
-                    let mut url = format!(
-                        "https://api.spotify.com/v1/me/player/volume?volume_percent={}",
-                        volume
-                    );
-                    url.push_str(&format!("&device_id={}", device_id));
-                    let authorization_header = format!("Bearer {}", access_token);
-
-                    let mut headers = HashMap::new();
-                    headers.insert("Authorization".to_owned(), authorization_header);
-
-                    match fetch(&url, FetchMethod::Put, "", headers, true).await {
-                        Err(e) => console::log_1(&format!("Error changing volume: {:?}", e).into()),
-                        Ok(_) => {} // volume successfully updated
-                    }
-                }
-                Err(e) => console::log_1(
-                    &format!("Error while authenticating to Spotify API: {:?}", e).into(),
-                ),
-            }
+            let url = format!(
+                "{}/me/player/volume?volume_percent={}&device_id={}",
+                API_URL, volume, device_id
+            );
 
-            Err(JsValue::from("Error updating volume"))
+            api.request(FetchMethod::Put, &url, "", true)
+                .await
+                .map_err(|e| log_error("Error changing volume", e))
         })
     }
 
     /// Get available Spotify devices.
     pub fn get_devices(&self) -> Promise {
-        let authorize_request = self.authorize();
+        let api = self.clone();
 
         future_to_promise(async move {
-            match JsFuture::from(authorize_request).await {
-                Ok(authorize_request) => {
-                    let access_token: String = authorize_request
-                        .as_string()
-                        .expect("Error authenticating to Spotify API");
-
-                    let url = "https://api.spotify.com/v1/me/player/devices";
-                    let authorization_header = format!("Bearer {}", access_token);
-
-                    let mut headers = HashMap::new();
-                    headers.insert("Authorization".to_owned(), authorization_header);
-
-                    match fetch(url, FetchMethod::Get, "", headers, false).await {
-                        Err(e) => console::log_1(&format!("Error getting devices: {:?}", e).into()),
-                        Ok(result) => {
-                            return Ok(result);
-                        }
-                    }
-                }
-                Err(e) => console::log_1(
-                    &format!("Error while authenticating to Spotify API: {:?}", e).into(),
-                ),
+            let url = format!("{}/me/player/devices", API_URL);
+
+            api.request(FetchMethod::Get, &url, "", false)
+                .await
+                .map_err(|e| log_error("Error fetching devices", e))
+        })
+    }
+
+    /// Get the current playback state across all devices.
+    /// Resolves to `null` if nothing is being played.
+    pub fn get_playback_state(&self) -> Promise {
+        let api = self.clone();
+
+        future_to_promise(async move {
+            let url = format!("{}/me/player?additional_types={}", API_URL, ITEM_TYPES);
+
+            api.request(FetchMethod::Get, &url, "", false)
+                .await
+                .map_err(|e| log_error("Error fetching playback state", e))
+        })
+    }
+
+    /// Temporarily lower the volume of the playing device, or pause it if `volume` is undefined.
+    /// The previous state gets restored after `duration` milliseconds or by calling `unduck`.
+    /// Resolves to `false` if nothing is playing.
+    pub fn duck(&self, volume: Option<u32>, duration: u32) -> Promise {
+        let api = self.clone();
+
+        future_to_promise(async move {
+            ducking::duck(&api, &api.ducking, volume, duration)
+                .await
+                .map(JsValue::from)
+                .map_err(|e| log_error("Error ducking playback", e))
+        })
+    }
+
+    /// Restore the playback state from before ducking right away.
+    pub fn unduck(&self) -> Promise {
+        let api = self.clone();
+
+        future_to_promise(async move {
+            ducking::restore(&api, &api.ducking)
+                .await
+                .map(|_| JsValue::UNDEFINED)
+                .map_err(|e| log_error("Error restoring ducked playback", e))
+        })
+    }
+
+    /// Check if playback is currently ducked.
+    pub fn is_ducked(&self) -> bool {
+        self.ducking.borrow().is_ducked()
+    }
+
+    /// Capture the playback state under the provided name.
+    /// Resolves to the snapshot, or to `null` if no device is active.
+    pub fn save_snapshot(&self, name: String) -> Promise {
+        let api = self.clone();
+
+        future_to_promise(async move {
+            let snapshot = snapshot::capture(&api)
+                .await
+                .map_err(|e| log_error("Error capturing playback state", e))?;
+            #[allow(deprecated)]
+            let value = JsValue::from_serde(&snapshot).unwrap_or(JsValue::NULL);
+
+            if let Some(snapshot) = snapshot {
+                api.store.record_snapshot(&name, snapshot);
             }
+            Ok(value)
+        })
+    }
+
+    /// Restore the playback state captured under the provided name.
+    pub fn restore_snapshot(&self, name: String) -> Promise {
+        let api = self.clone();
+
+        future_to_promise(async move {
+            let snapshot = api.store.snapshot(&name).ok_or_else(|| {
+                log_error(
+                    "Error restoring playback state",
+                    format!("no snapshot named {}", name),
+                )
+            })?;
 
-            Err(JsValue::from("Error fetching devices"))
+            snapshot::restore(&api, &snapshot)
+                .await
+                .map(|_| JsValue::UNDEFINED)
+                .map_err(|e| log_error("Error restoring playback state", e))
         })
     }
 
+    /// Forget a snapshot, returns whether it existed.
+    pub fn delete_snapshot(&self, name: String) -> bool {
+        self.store.remove_snapshot(&name)
+    }
+
+    /// Check if a snapshot with the provided name exists.
+    pub fn has_snapshot(&self, name: String) -> bool {
+        self.store.snapshot(&name).is_some()
+    }
+
     /// Make an authorization request.
     pub fn authorize(&self) -> Promise {
-        let mut refresh_token = Rc::clone(&self.refresh_token);
-        let mut access_token = Rc::clone(&self.access_token);
-        let mut access_token_timestamp = Rc::clone(&self.access_token_timestamp);
+        let refresh_token = Rc::clone(&self.refresh_token);
+        let access_token = Rc::clone(&self.access_token);
+        let access_token_timestamp = Rc::clone(&self.access_token_timestamp);
 
         let url = "https://accounts.spotify.com/api/token";
         let token = format!("{}:{}", self.client_id, self.client_secret);
@@ -324,6 +846,10 @@ impl SpotifyApi {
         let authorization_header = format!("Basic {}", base64_token);
 
         future_to_promise(async move {
+            if Date::now() - access_token_timestamp.get() <= ACCESS_TOKEN_LIFETIME {
+                return Ok(JsValue::from(access_token.borrow().clone()));
+            }
+
             let mut headers = HashMap::new();
             headers.insert(
                 "Content-Type".to_owned(),
@@ -331,19 +857,19 @@ impl SpotifyApi {
             );
             headers.insert("Authorization".to_owned(), authorization_header);
 
-            let body = format!("grant_type=refresh_token&refresh_token={}", *refresh_token);
-
-            if Date::now() - *access_token_timestamp <= ACCESS_TOKEN_LIFETIME {
-                return Ok(JsValue::from((*access_token).clone()));
-            }
+            let body = format!(
+                "grant_type=refresh_token&refresh_token={}",
+                refresh_token.borrow()
+            );
 
             if let Ok(result) = fetch(url, FetchMethod::Post, &body, headers, false).await {
+                #[allow(deprecated)]
                 let json: Result<SpotifyAuthorization, _> = result.into_serde();
 
-                return match json {
+                match json {
                     Ok(json) => {
-                        access_token_timestamp = Rc::new(Date::now());
-                        access_token = Rc::new(json.access_token.clone());
+                        access_token_timestamp.set(Date::now());
+                        access_token.replace(json.access_token.clone());
 
                         // todo: never called, and if then it'll fail
                         if let Some(new_refresh_token) = json.refresh_token {
@@ -352,23 +878,525 @@ impl SpotifyApi {
 
                             let config_string = fs.read_file(HOMEBRIDGE_CONFIG);
                             let new_config_string = config_string
-                                .replace(&(*refresh_token), &new_refresh_token.clone());
+                                .replace(refresh_token.borrow().as_str(), &new_refresh_token);
                             fs.write_file(HOMEBRIDGE_CONFIG, new_config_string);
-                            refresh_token = Rc::new(new_refresh_token);
+                            refresh_token.replace(new_refresh_token);
                         }
 
                         Ok(JsValue::from(json.access_token))
                     }
                     Err(_) => {
-                        console::log_1(
-                            &format!("Error while retrieving access token from Spotify API. Response was: {:?}", result).into(),
-                        );
-                        Err(JsValue::from(format!("Error while retrieving access token from Spotify API. Response was: {:?}", result)))
+                        let response = JSON::stringify(&result)
+                            .map(String::from)
+                            .unwrap_or_default();
+
+                        Err(log_error(
+                            "Error retrieving access token from Spotify API",
+                            format!("unexpected response {}", logger::redact(&response)),
+                        ))
+                    }
+                }
+            } else {
+                Err(log_error(
+                    "Error retrieving access token from Spotify API",
+                    "request failed",
+                ))
+            }
+        })
+    }
+}
+
+impl SpotifyApi {
+    /// Create the API, remembering snapshots and ducked playback in the provided store.
+    pub fn with_store(
+        client_id: String,
+        client_secret: String,
+        refresh_token: String,
+        store: Rc<StateStore>,
+    ) -> SpotifyApi {
+        SpotifyApi {
+            client_id,
+            client_secret,
+            access_token: Rc::new(RefCell::new("".to_owned())),
+            refresh_token: Rc::new(RefCell::new(refresh_token)),
+            access_token_timestamp: Rc::new(Cell::new(0.0)),
+            ducking: Rc::new(RefCell::new(Ducking::new(store.clone()))),
+            store,
+        }
+    }
+
+    /// Restore ducked playback right away, e.g. when Homebridge shuts down.
+    pub fn stop_ducking(&self) {
+        ducking::restore_now(self, &self.ducking);
+    }
+
+    /// Restore the volume that was still ducked when Homebridge stopped.
+    pub fn resume_ducking(&self) {
+        ducking::resume(self, &self.ducking);
+    }
+
+    /// Call `listener` with `true` when ducking starts and with `false` once it ends.
+    pub fn add_ducking_listener(&self, listener: Function) {
+        self.ducking.borrow_mut().add_listener(listener);
+    }
+
+    /// Find an available device by its name or ID.
+    pub async fn find_device(&self, name_or_id: &str) -> Result<Option<SpotifyDevice>, JsValue> {
+        #[allow(deprecated)]
+        let devices: SpotifyDevices = JsFuture::from(self.get_devices())
+            .await?
+            .into_serde()
+            .map_err(|e| log_error("Error parsing devices", e.to_string()))?;
+
+        Ok(devices
+            .devices
+            .into_iter()
+            .find(|d| d.id == name_or_id || d.name == name_or_id))
+    }
+
+    /// Return the URI of the track or episode being played, if it can be saved to the library.
+    async fn current_item_uri(&self) -> Result<Option<String>, JsValue> {
+        #[allow(deprecated)]
+        let playback: Option<SpotifyPlayback> = JsFuture::from(self.get_playback_state())
+            .await?
+            .into_serde()
+            .map_err(|e| log_error("Error parsing playback state", e.to_string()))?;
+
+        Ok(playback
+            .and_then(|p| p.item)
+            .and_then(|item| item.saveable_uri().map(str::to_owned)))
+    }
+
+    /// Check whether a track or episode is saved in the library.
+    #[allow(deprecated)]
+    pub async fn is_saved(&self, uri: &str) -> Result<bool, JsValue> {
+        let (collection, id) = library_item(uri).ok_or_else(|| {
+            log_error("Error checking library", format!("{} can't be saved", uri))
+        })?;
+        let url = format!("{}/me/{}/contains?ids={}", API_URL, collection, id);
+
+        let result = self
+            .request(FetchMethod::Get, &url, "", false)
+            .await
+            .map_err(|e| log_error("Error checking library", e))?;
+
+        match result.into_serde::<Vec<bool>>() {
+            Ok(saved) => Ok(saved.first().copied().unwrap_or(false)),
+            Err(e) => Err(log_error("Error parsing library state", e.to_string())),
+        }
+    }
+
+    /// Save a track or episode to the library, or remove it.
+    pub async fn set_saved(&self, uri: &str, saved: bool) -> Result<(), JsValue> {
+        let (collection, id) = library_item(uri).ok_or_else(|| {
+            log_error("Error changing library", format!("{} can't be saved", uri))
+        })?;
+        let url = format!("{}/me/{}?ids={}", API_URL, collection, id);
+        let method = if saved {
+            FetchMethod::Put
+        } else {
+            FetchMethod::Delete
+        };
+
+        self.request(method, &url, "", true)
+            .await
+            .map(|_| ())
+            .map_err(|e| log_error("Error changing library", e))
+    }
+
+    /// Search for items of the provided types, returns up to `limit` results per type.
+    pub async fn search_items(
+        &self,
+        query: &str,
+        types: &[&str],
+        limit: usize,
+    ) -> Result<HashMap<String, Vec<SpotifyEntry>>, JsValue> {
+        if let Some(kind) = types.iter().find(|t| !SEARCH_TYPES.contains(t)) {
+            return Err(log_error(
+                "Error searching",
+                format!("unsupported type \"{}\"", kind),
+            ));
+        }
+
+        let mut results = HashMap::new();
+        for kind in types {
+            let url = format!(
+                "{}/search?q={}&type={}&limit={}",
+                API_URL,
+                encode_component(query),
+                kind,
+                limit.clamp(1, PAGE_SIZE)
+            );
+
+            // results are keyed by the plural of the type, e.g. `playlists`
+            let key = format!("{}s", kind);
+            let items = self
+                .paginate(
+                    &url,
+                    Some(limit),
+                    |mut pages: HashMap<String, Page<SpotifyEntry>>| pages.remove(&key),
+                )
+                .await?;
+
+            results.insert(key, items);
+        }
+
+        Ok(results)
+    }
+
+    /// Return the playlists owned or followed by the user, all of them if `max_items` is `None`.
+    pub async fn playlists(&self, max_items: Option<usize>) -> Result<Vec<SpotifyEntry>, JsValue> {
+        let url = format!("{}/me/playlists?limit={}", API_URL, PAGE_SIZE);
+
+        self.paginate(&url, max_items, Some).await
+    }
+
+    /// Return the albums saved in the library, all of them if `max_items` is `None`.
+    pub async fn saved_albums(
+        &self,
+        max_items: Option<usize>,
+    ) -> Result<Vec<SpotifyEntry>, JsValue> {
+        let url = format!("{}/me/albums?limit={}", API_URL, PAGE_SIZE);
+        let albums: Vec<SavedAlbum> = self.paginate(&url, max_items, Some).await?;
+
+        Ok(albums.into_iter().map(|saved| saved.album).collect())
+    }
+
+    /// Return the artists followed by the user, all of them if `max_items` is `None`.
+    pub async fn followed_artists(
+        &self,
+        max_items: Option<usize>,
+    ) -> Result<Vec<SpotifyEntry>, JsValue> {
+        let url = format!("{}/me/following?type=artist&limit={}", API_URL, PAGE_SIZE);
+
+        self.paginate(&url, max_items, |response: FollowedArtists| {
+            Some(response.artists)
+        })
+        .await
+    }
+
+    /// Collect the items of a list starting at `url`, following `next` links or cursors until
+    /// the last page or until `max_items` are collected.
+    /// `page` returns the paging object of a response, e.g. `artists` when listing followed artists.
+    async fn paginate<R, T, F>(
+        &self,
+        url: &str,
+        max_items: Option<usize>,
+        page: F,
+    ) -> Result<Vec<T>, JsValue>
+    where
+        R: DeserializeOwned,
+        F: Fn(R) -> Option<Page<T>>,
+    {
+        let mut items = Vec::new();
+        let mut next = Some(url.to_owned());
+
+        while let Some(url) = next {
+            let current = match page(self.get_json(&url).await?) {
+                Some(current) => current,
+                None => break,
+            };
+
+            next = current.next_url(&url);
+            items.extend(current.items.into_iter().flatten());
+
+            if max_items.is_some_and(|max| items.len() >= max) {
+                break;
+            }
+        }
+
+        if let Some(max) = max_items {
+            items.truncate(max);
+        }
+
+        Ok(items)
+    }
+
+    /// Start playback on a device with the provided context, tracks and position.
+    pub async fn start_playback(
+        &self,
+        device_id: &str,
+        request: &PlayRequest,
+    ) -> Result<JsValue, JsValue> {
+        request
+            .validate()
+            .map_err(|e| log_error("Invalid content to play", e))?;
+
+        let url = format!("{}/me/player/play?device_id={}", API_URL, device_id);
+        let body = serde_json::to_string(request).unwrap_or_default();
+
+        self.request(FetchMethod::Put, &url, &body, true)
+            .await
+            .map_err(|e| log_error("Error starting playback", e))
+    }
+
+    /// Make an authorized GET request and parse the response.
+    #[allow(deprecated)]
+    async fn get_json<T: DeserializeOwned>(&self, url: &str) -> Result<T, JsValue> {
+        self.request(FetchMethod::Get, url, "", false)
+            .await
+            .map_err(|e| log_error("Error fetching list", e))?
+            .into_serde()
+            .map_err(|e| log_error("Error parsing list", e.to_string()))
+    }
+
+    /// Make an authorized request to the Spotify Web API.
+    /// Rate limited requests are retried after the time requested by Spotify.
+    async fn request(
+        &self,
+        method: FetchMethod,
+        url: &str,
+        body: &str,
+        empty_response: bool,
+    ) -> Result<JsValue, JsValue> {
+        let mut retries = 0;
+
+        loop {
+            match self.send(method, url, body, empty_response).await {
+                Err(e) if error_status(&e) == Some(429) && retries < MAX_RETRIES => {
+                    let seconds = error_retry_after(&e).unwrap_or(DEFAULT_RETRY_AFTER);
+                    if seconds > MAX_RETRY_AFTER {
+                        return Err(e);
                     }
-                };
+
+                    logger::warn(&format!(
+                        "Rate limited by Spotify, retrying in {} seconds",
+                        seconds
+                    ));
+                    sleep(seconds * 1000).await;
+                    retries += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Send a single authorized request to the Spotify Web API.
+    async fn send(
+        &self,
+        method: FetchMethod,
+        url: &str,
+        body: &str,
+        empty_response: bool,
+    ) -> Result<JsValue, JsValue> {
+        let access_token = JsFuture::from(self.authorize())
+            .await?
+            .as_string()
+            .ok_or_else(|| JsValue::from("Invalid access token"))?;
+
+        let mut headers = HashMap::new();
+        headers.insert(
+            "Authorization".to_owned(),
+            format!("Bearer {}", access_token),
+        );
+        if !body.is_empty() {
+            headers.insert("Content-Type".to_owned(), "application/json".to_owned());
+        }
+
+        fetch(url, method, body, headers, empty_response).await
+    }
+}
+
+/// Return the library collection and ID of a track or episode URI.
+fn library_item(uri: &str) -> Option<(&'static str, &str)> {
+    if let Some(id) = uri.strip_prefix("spotify:track:") {
+        Some(("tracks", id))
+    } else {
+        uri.strip_prefix("spotify:episode:")
+            .map(|id| ("episodes", id))
+    }
+}
+
+/// Set a query parameter of a URL, replacing its previous value.
+fn with_query_param(url: &str, name: &str, value: &str) -> String {
+    let (base, query) = url.split_once('?').unwrap_or((url, ""));
+
+    let mut params: Vec<&str> = query
+        .split('&')
+        .filter(|param| !param.is_empty() && param.split('=').next() != Some(name))
+        .collect();
+    let param = format!("{}={}", name, encode_component(value));
+    params.push(&param);
+
+    format!("{}?{}", base, params.join("&"))
+}
+
+/// Percent-encode a URL component like `encodeURIComponent` does.
+fn encode_component(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| {
+            if b.is_ascii_alphanumeric() || b"-_.!~*'()".contains(&b) {
+                (b as char).to_string()
             } else {
-                Err(JsValue::from("Error executing fetch request"))
+                format!("%{:02X}", b)
             }
         })
+        .collect()
+}
+
+/// Log an error and return it so that it can be passed on.
+fn log_error<E: Into<JsValue>>(message: &str, error: E) -> JsValue {
+    let error = error.into();
+    logger::error(&format!("{}: {}", message, error_message(&error)));
+    error
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn play_request(value: Value) -> PlayRequest {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn play_offset_forms() {
+        let request = play_request(json!({
+            "context_uri": "spotify:album:1",
+            "offset": { "position": 5 },
+        }));
+        assert!(matches!(
+            request.offset,
+            Some(PlayOffset::Position { position: 5 })
+        ));
+
+        let request = play_request(json!({
+            "context_uri": "spotify:album:1",
+            "offset": { "uri": "spotify:track:2" },
+        }));
+        assert!(matches!(
+            request.offset,
+            Some(PlayOffset::Uri { ref uri }) if uri == "spotify:track:2"
+        ));
+
+        assert!(
+            serde_json::from_value::<PlayRequest>(json!({ "offset": { "index": 5 } })).is_err()
+        );
+    }
+
+    #[test]
+    fn play_request_serializes_set_fields() {
+        let request = play_request(json!({
+            "uris": ["spotify:track:1"],
+            "offset": { "position": 0 },
+        }));
+        assert_eq!(
+            serde_json::to_value(&request).unwrap(),
+            json!({ "uris": ["spotify:track:1"], "offset": { "position": 0 } })
+        );
+    }
+
+    #[test]
+    fn play_request_rejects_unknown_fields() {
+        assert!(
+            serde_json::from_value::<PlayRequest>(json!({ "context": "spotify:album:1" })).is_err()
+        );
+    }
+
+    #[test]
+    fn validate_play_request() {
+        assert!(PlayRequest::default().validate().is_ok());
+        assert!(
+            play_request(json!({ "context_uri": "spotify:album:1", "position_ms": 1000 }))
+                .validate()
+                .is_ok()
+        );
+        assert!(play_request(json!({ "uris": ["spotify:track:1"] }))
+            .validate()
+            .is_ok());
+
+        assert_eq!(
+            play_request(json!({ "context_uri": "spotify:album:1", "uris": ["spotify:track:1"] }))
+                .validate(),
+            Err("either context_uri or uris can be provided, not both".to_owned())
+        );
+        assert_eq!(
+            play_request(json!({ "uris": [] })).validate(),
+            Err("uris must not be empty".to_owned())
+        );
+        assert_eq!(
+            play_request(json!({ "offset": { "position": 1 } })).validate(),
+            Err("offset requires context_uri or uris".to_owned())
+        );
+    }
+
+    fn page(value: Value) -> Page<SpotifyEntry> {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn entry() -> Value {
+        json!({ "type": "artist", "uri": "spotify:artist:1", "name": "Artist" })
+    }
+
+    #[test]
+    fn next_url_of_offset_page() {
+        let next = "https://api.spotify.com/v1/me/albums?offset=50&limit=50";
+
+        assert_eq!(
+            page(json!({ "items": [entry()], "next": next })).next_url("unused"),
+            Some(next.to_owned())
+        );
+        assert_eq!(
+            page(json!({ "items": [entry()], "next": null })).next_url("unused"),
+            None
+        );
+    }
+
+    #[test]
+    fn next_url_of_cursor_page() {
+        let url = "https://api.spotify.com/v1/me/following?type=artist&limit=50";
+
+        assert_eq!(
+            page(json!({ "items": [entry()], "next": null, "cursors": { "after": "abc" } }))
+                .next_url(url),
+            Some(format!("{}&after=abc", url))
+        );
+        assert_eq!(
+            page(json!({ "items": [], "next": null, "cursors": { "after": "abc" } })).next_url(url),
+            None
+        );
+        assert_eq!(
+            page(json!({ "items": [entry()], "next": null, "cursors": { "after": null } }))
+                .next_url(url),
+            None
+        );
+    }
+
+    #[test]
+    fn page_skips_null_items() {
+        let page = page(json!({ "items": [null, entry()], "next": null }));
+        assert_eq!(page.items.into_iter().flatten().count(), 1);
+    }
+
+    #[test]
+    fn replace_query_param() {
+        assert_eq!(
+            with_query_param("https://example.com/list", "after", "a"),
+            "https://example.com/list?after=a"
+        );
+        assert_eq!(
+            with_query_param(
+                "https://example.com/list?type=artist&after=a&limit=50",
+                "after",
+                "b"
+            ),
+            "https://example.com/list?type=artist&limit=50&after=b"
+        );
+        assert_eq!(
+            with_query_param("https://example.com/list?", "after", "a b&c"),
+            "https://example.com/list?after=a%20b%26c"
+        );
+    }
+
+    #[test]
+    fn encode_like_encode_uri_component() {
+        assert_eq!(encode_component("AZaz09-_.!~*'()"), "AZaz09-_.!~*'()");
+        assert_eq!(
+            encode_component("spotify:track:1 /?#&="),
+            "spotify%3Atrack%3A1%20%2F%3F%23%26%3D"
+        );
+        assert_eq!(encode_component("Björk"), "Bj%C3%B6rk");
     }
 }
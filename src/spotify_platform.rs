@@ -1,22 +1,29 @@
 //! Defines the Homebridge Spotify Platform.
 
+use crate::alarm::Alarm;
+use crate::duck_switch::DuckSwitch;
+use crate::preset_switch::PresetSwitch;
+use crate::snapshot_switch::SnapshotSwitch;
 use crate::spotify_api::SpotifyApi;
 use js_sys::Function;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::rc::Rc;
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::JsCast;
 use wasm_bindgen_futures::spawn_local;
 use wasm_bindgen_futures::JsFuture;
-use web_sys::console;
 
-use crate::spotify_accessory::Accessory;
-use crate::spotify_accessory::SpotifyAccessory;
-use crate::spotify_api::SpotifyDevices;
+use crate::config::{Config, ServiceType, PLATFORM_NAME};
+use crate::logger::{self, Log};
+use crate::node_fetch::error_message;
+use crate::poll_scheduler::{PollIntervals, PollScheduler, FAST_REFRESH_RATE, SLOW_REFRESH_RATE};
+use crate::spotify_accessory::{Accessory, UUIDGen};
+use crate::spotify_accessory::{AccessoryContext, SpotifyAccessory};
+use crate::spotify_api::{SpotifyDevices, SpotifyPlayback};
+use crate::spotify_state::{SharedState, SpotifyState};
+use crate::state_store::StateStore;
 
-const REFRESH_RATE: u32 = 10 * 1000; // milliseconds
 const PLUGIN_IDENTIFIER: &str = "homebridge-rusty-spotify";
-const PLATFORM_NAME: &str = "Spotify";
 
 #[wasm_bindgen]
 extern "C" {
@@ -52,32 +59,13 @@ extern "C" {
     #[wasm_bindgen(method)]
     fn on(this: &Homebridge, event: &str, listener: &Function);
 
-    #[wasm_bindgen(js_name = setInterval)]
-    pub fn set_interval(closure: &Function, millis: u32) -> f64;
-}
+    type User;
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-/// Service used for accessories. HomeKit doesn't support Speaker, instead use Light.
-pub enum ServiceType {
-    #[serde(rename = "light")]
-    Light,
-    #[serde(rename = "speaker")]
-    Speaker,
-}
+    #[wasm_bindgen(method, getter)]
+    fn user(this: &Homebridge) -> User;
 
-#[derive(Serialize, Deserialize)]
-/// Represents the platform configuration retrieved from ~/.homebridge/config.json
-struct Config {
-    /// Spotify API client_id
-    pub client_id: String,
-    /// Spotify API client_secret
-    pub client_secret: String,
-    /// Cached refresh token for Spotify API
-    pub refresh_token: String,
-    /// Device refresh rate
-    pub refresh_rate: Option<u32>,
-    /// Service type for new accessories
-    pub service_type: Option<ServiceType>,
+    #[wasm_bindgen(method, js_name = storagePath)]
+    fn storage_path(this: &User) -> String;
 }
 
 #[wasm_bindgen]
@@ -91,34 +79,212 @@ pub struct SpotifyPlatform {
     api: Rc<SpotifyApi>,
     /// Available Spotify devices
     devices: Rc<RefCell<Vec<SpotifyAccessory>>>,
-    /// Cached accessories
+    /// Cached accessories that don't belong to a remembered device,
+    /// removed unless they belong to a device found during the first refresh
     cached_devices: Rc<RefCell<Vec<Accessory>>>,
+    /// Spotify state retrieved during the last refresh
+    state: SharedState,
+    /// Schedules device refreshes
+    scheduler: Rc<PollScheduler>,
+    /// Switches that don't belong to a Spotify device
+    switches: Rc<Switches>,
+    /// Device state remembered across restarts
+    store: Rc<StateStore>,
+}
+
+#[derive(Debug, Default)]
+/// Represents the switch accessories that don't belong to a Spotify device.
+struct Switches {
+    /// Wake-up alarms
+    alarms: Vec<Alarm>,
+    /// Switch ducking playback
+    ducking: Option<DuckSwitch>,
+    /// Switches capturing and restoring the playback state
+    snapshots: Vec<SnapshotSwitch>,
+    /// Switches playing or queueing specific content
+    presets: Vec<PresetSwitch>,
+}
+
+impl Switches {
+    /// Return the names and accessories of all switches.
+    fn accessories(&self) -> Vec<(&str, &Accessory)> {
+        let alarms = self
+            .alarms
+            .iter()
+            .map(|alarm| (alarm.get_name(), alarm.get_accessory()));
+        let ducking = self
+            .ducking
+            .iter()
+            .map(|switch| (switch.get_name(), switch.get_accessory()));
+        let snapshots = self
+            .snapshots
+            .iter()
+            .map(|switch| (switch.get_name(), switch.get_accessory()));
+        let presets = self
+            .presets
+            .iter()
+            .map(|switch| (switch.get_name(), switch.get_accessory()));
+
+        alarms
+            .chain(ducking)
+            .chain(snapshots)
+            .chain(presets)
+            .collect()
+    }
+
+    /// Stop the timers of the switches, e.g. when Homebridge shuts down.
+    fn stop(&self) {
+        for alarm in &self.alarms {
+            alarm.stop();
+        }
+    }
 }
 
 #[wasm_bindgen]
 impl SpotifyPlatform {
     #[wasm_bindgen(constructor)]
-    pub fn new(homebridge: Homebridge, _log: Function, config: &JsValue) -> SpotifyPlatform {
-        let config: Config = config.into_serde().expect("Error loading config.");
+    pub fn new(homebridge: Homebridge, log: Log, config: &JsValue) -> SpotifyPlatform {
+        logger::init(log);
+
+        let (config, valid) = match Config::from_js(config) {
+            Ok(config) => (config, true),
+            Err(errors) => {
+                for error in errors {
+                    logger::error(&format!("Invalid config: {}", error));
+                }
+                logger::error("Spotify platform is disabled until the config is fixed");
+                (Config::default(), false)
+            }
+        };
+        logger::set_debug(config.debug.unwrap_or(false));
 
-        let api = SpotifyApi::new(
+        let storage_path = if valid {
+            Some(homebridge.user().storage_path())
+        } else {
+            None
+        };
+        let store = Rc::new(StateStore::load(storage_path));
+
+        let api = SpotifyApi::with_store(
             config.client_id.clone(),
             config.client_secret.clone(),
             config.refresh_token.clone(),
+            store.clone(),
         );
 
+        let scheduler = Rc::new(PollScheduler::new(PollIntervals {
+            fast: config.fast_refresh_rate.unwrap_or(FAST_REFRESH_RATE),
+            slow: config.refresh_rate.unwrap_or(SLOW_REFRESH_RATE),
+            idle: config.idle_refresh_rate,
+        }));
+
+        let api = Rc::new(api);
+        let devices = Rc::new(RefCell::new(Vec::new()));
+
+        let switches = if valid {
+            Switches {
+                alarms: config
+                    .alarms
+                    .iter()
+                    .flatten()
+                    .map(|alarm| {
+                        Alarm::new(
+                            alarm.clone(),
+                            api.clone(),
+                            devices.clone(),
+                            scheduler.clone(),
+                        )
+                    })
+                    .collect(),
+                ducking: config
+                    .ducking
+                    .clone()
+                    .map(|ducking| DuckSwitch::new(ducking, api.clone(), scheduler.clone())),
+                snapshots: config
+                    .snapshots
+                    .iter()
+                    .flatten()
+                    .map(|snapshot| {
+                        SnapshotSwitch::new(snapshot.clone(), api.clone(), scheduler.clone())
+                    })
+                    .collect(),
+                presets: config
+                    .presets
+                    .iter()
+                    .flatten()
+                    .map(|preset| {
+                        PresetSwitch::new(
+                            preset.clone(),
+                            api.clone(),
+                            store.clone(),
+                            scheduler.clone(),
+                        )
+                    })
+                    .collect(),
+            }
+        } else {
+            Switches::default()
+        };
+
         let mut platform = SpotifyPlatform {
             homebridge,
             config,
-            api: Rc::new(api),
-            devices: Rc::new(RefCell::new(Vec::new())),
+            api,
+            devices,
             cached_devices: Rc::new(RefCell::new(Vec::new())),
+            state: Rc::new(RefCell::new(SpotifyState::default())),
+            scheduler,
+            switches: Rc::new(switches),
+            store,
         };
 
-        platform.refresh_devices();
+        // a platform with an invalid config doesn't refresh devices and only keeps
+        // the cached accessories so that they don't get removed from HomeKit
+        if valid {
+            platform.refresh_devices();
+            platform.handle_lifecycle_events();
+        }
         platform
     }
 
+    /// Start refreshing devices once Homebridge restored all cached accessories
+    /// and stop refreshing when Homebridge shuts down.
+    fn handle_lifecycle_events(&self) {
+        let scheduler = self.scheduler.clone();
+        let api = self.api.clone();
+        let did_finish_launching = Closure::wrap(Box::new(move || {
+            scheduler.schedule(0);
+            api.resume_ducking();
+        }) as Box<dyn FnMut()>);
+
+        let scheduler = self.scheduler.clone();
+        let switches = self.switches.clone();
+        let api = self.api.clone();
+        let devices = self.devices.clone();
+        let store = self.store.clone();
+        let shutdown = Closure::wrap(Box::new(move || {
+            logger::info("Stop refreshing Spotify devices");
+            scheduler.stop();
+            switches.stop();
+            api.stop_ducking();
+            for device in devices.borrow().iter() {
+                device.stop();
+            }
+            store.flush();
+        }) as Box<dyn FnMut()>);
+
+        self.homebridge.on(
+            "didFinishLaunching",
+            did_finish_launching.as_ref().unchecked_ref(),
+        );
+        self.homebridge
+            .on("shutdown", shutdown.as_ref().unchecked_ref());
+
+        // Homebridge keeps the listeners for the lifetime of the platform
+        did_finish_launching.forget();
+        shutdown.forget();
+    }
+
     /// Determine available Spotify devices and add them as accessories,
     /// remove devices that became inactive.
     fn refresh_devices(&mut self) {
@@ -126,54 +292,108 @@ impl SpotifyPlatform {
         let api = self.api.clone();
         let cached = self.cached_devices.clone();
         let devices = self.devices.clone();
-        let service_type = if self.config.service_type.is_some() {
-            self.config
-                .service_type
-                .clone()
-                .expect("Error creating homebridge-rusty-spotify service.")
-        } else {
-            // use light as the default service type since it is supported
-            // by most platforms.
-            ServiceType::Light
-        };
+        let state = self.state.clone();
+        let scheduler = self.scheduler.clone();
+        let config = self.config.clone();
+        let switches = self.switches.clone();
+        let switches_registered = Rc::new(Cell::new(false));
+        let store = self.store.clone();
 
         let refresh_closure = Closure::wrap(Box::new(move || {
             let homebridge = homebridge.clone();
             let api = api.clone();
             let cached = cached.clone();
             let devices = devices.clone();
-            let service_type = service_type.clone();
+            let state = state.clone();
+            let scheduler = scheduler.clone();
+            let config = config.clone();
+            let switches = switches.clone();
+            let switches_registered = switches_registered.clone();
+            let store = store.clone();
 
             spawn_local(async move {
-                Self::remove_cached(&homebridge, cached);
-
-                let available_devices: SpotifyDevices =
-                    match JsFuture::from(api.get_devices()).await {
-                        Ok(state) => state.into_serde().unwrap_or(SpotifyDevices {
-                            devices: Vec::new(),
-                        }),
-                        Err(_) => SpotifyDevices {
-                            devices: Vec::new(),
-                        },
-                    };
+                // switches don't depend on the available devices, register them once
+                if !switches_registered.replace(true) {
+                    Self::register_switches(&homebridge, &switches, &cached);
+                }
+
+                let devices_request = JsFuture::from(api.get_devices()).await;
+
+                // Homebridge might have shut down while waiting for Spotify
+                if scheduler.is_stopped() {
+                    return;
+                }
+
+                #[allow(deprecated)]
+                let available_devices: SpotifyDevices = match devices_request {
+                    Ok(state) => state.into_serde().unwrap_or(SpotifyDevices {
+                        devices: Vec::new(),
+                    }),
+                    Err(e) => {
+                        // keep the current devices instead of unregistering them
+                        // because of a temporary error
+                        state.borrow_mut().error = Some(error_message(&e));
+                        let activity = state.borrow().activity();
+                        scheduler.schedule_next(activity, false);
+                        return;
+                    }
+                };
+
+                // playback state is shared by all devices, so it only needs to be requested once
+                let playback_request = JsFuture::from(api.get_playback_state()).await;
+
+                if scheduler.is_stopped() {
+                    return;
+                }
+
+                #[allow(deprecated)]
+                let playback =
+                    playback_request
+                        .map_err(|e| error_message(&e))
+                        .and_then(|playback| {
+                            playback
+                                .into_serde::<Option<SpotifyPlayback>>()
+                                .map_err(|e| {
+                                    logger::error(&format!("Error parsing playback state: {}", e));
+                                    e.to_string()
+                                })
+                        });
+
+                let playback = match playback {
+                    Ok(playback) => playback,
+                    Err(e) => {
+                        // keep the current state instead of turning every device off
+                        // because of a temporary error
+                        state.borrow_mut().error = Some(e);
+                        let activity = state.borrow().activity();
+                        scheduler.schedule_next(activity, false);
+                        return;
+                    }
+                };
+
+                let previous = state.replace(SpotifyState {
+                    devices: available_devices.devices,
+                    playback,
+                    error: None,
+                });
+                let state_ref = state.borrow();
+                store.record(&state_ref);
+                Self::log_now_playing(&previous, &state_ref);
 
                 // check if devices still exist
                 devices.borrow_mut().retain(|registered_device| {
-                    if !available_devices
+                    if !state_ref
                         .devices
                         .iter()
-                        .any(|d| &d.id == registered_device.get_device_id())
+                        .any(|d| d.id == registered_device.get_device_id())
                     {
                         let accessories =
                             PlatformAccessories::of(registered_device.get_accessory());
 
-                        console::log_1(
-                            &format!(
-                                "Unregister Spotify device: {:?}",
-                                registered_device.get_accessory()
-                            )
-                            .into(),
-                        );
+                        logger::info(&format!(
+                            "Unregister Spotify device: {}",
+                            registered_device.get_name()
+                        ));
 
                         homebridge.unregister_platform_accessories(
                             PLUGIN_IDENTIFIER,
@@ -187,51 +407,133 @@ impl SpotifyPlatform {
                 });
 
                 // check if device already exists, otherwise add
-                for available_device in available_devices.devices {
+                for available_device in state_ref.devices.iter() {
                     if !devices
                         .borrow()
                         .iter()
-                        .any(|d| d.get_device_id() == &available_device.id)
+                        .any(|d| d.get_device_id() == available_device.id)
                     {
-                        let accessory = SpotifyAccessory::new(
-                            available_device.name,
-                            available_device.id,
-                            service_type.clone(),
-                            api.clone(),
+                        let uuids =
+                            device_uuids(&config, &available_device.id, &available_device.name);
+                        let cached_accessory = {
+                            let mut cached = cached.borrow_mut();
+                            cached
+                                .iter()
+                                .position(|a| uuids.contains(&a.get_uuid()))
+                                .map(|index| cached.remove(index))
+                        };
+                        let restored = cached_accessory.is_some();
+
+                        let accessory = create_device(
+                            &config,
+                            &available_device.id,
+                            &available_device.name,
+                            AccessoryContext {
+                                api: api.clone(),
+                                state: state.clone(),
+                                scheduler: scheduler.clone(),
+                                store: store.clone(),
+                            },
+                            cached_accessory,
                         );
 
-                        console::log_1(
-                            &format!("Register Spotify device: {:?}", accessory.get_accessory())
-                                .into(),
-                        );
-
-                        let accessories = PlatformAccessories::of(accessory.get_accessory());
-
-                        homebridge.register_platform_accessories(
-                            PLUGIN_IDENTIFIER,
-                            PLATFORM_NAME,
-                            accessories.clone(),
-                        );
+                        if restored {
+                            logger::info(&format!(
+                                "Restore Spotify device: {}",
+                                accessory.get_name()
+                            ));
+                        } else {
+                            logger::info(&format!(
+                                "Register Spotify device: {}",
+                                accessory.get_name()
+                            ));
+
+                            homebridge.register_platform_accessories(
+                                PLUGIN_IDENTIFIER,
+                                PLATFORM_NAME,
+                                PlatformAccessories::of(accessory.get_accessory()),
+                            );
+                        }
 
                         devices.borrow_mut().push(accessory);
                     }
                 }
 
+                // cached accessories of devices that are gone
+                Self::remove_cached(&homebridge, &cached);
+
                 for device in devices.borrow().iter() {
-                    device.check_on();
+                    device.update_characteristics();
                 }
+
+                scheduler.schedule_next(state_ref.activity(), true);
             });
         }) as Box<dyn FnMut()>);
 
-        let _ = set_interval(
-            refresh_closure.as_ref().unchecked_ref(),
-            self.config.refresh_rate.unwrap_or(REFRESH_RATE),
-        );
-        refresh_closure.forget();
+        self.scheduler.set_callback(refresh_closure);
+    }
+
+    /// Log the track, episode or chapter whenever it changes.
+    fn log_now_playing(previous: &SpotifyState, current: &SpotifyState) {
+        let item = |state: &SpotifyState| {
+            state
+                .playback
+                .as_ref()
+                .and_then(|p| p.item.as_ref())
+                .and_then(|i| i.uri().map(str::to_owned))
+        };
+
+        if item(previous) == item(current) {
+            return;
+        }
+
+        if let Some(playback) = &current.playback {
+            if let Some(item) = &playback.item {
+                logger::debug(&format!(
+                    "Now playing on {}: {}",
+                    playback.device.name,
+                    item.description()
+                ));
+            }
+        }
+    }
+
+    /// Register the switches that don't belong to a Spotify device,
+    /// replacing their cached accessories.
+    fn register_switches(
+        homebridge: &Homebridge,
+        switches: &Switches,
+        cached: &RefCell<Vec<Accessory>>,
+    ) {
+        let accessories = switches.accessories();
+        let uuids: Vec<String> = accessories.iter().map(|(_, a)| a.get_uuid()).collect();
+
+        cached.borrow_mut().retain(|cached_accessory| {
+            if !uuids.contains(&cached_accessory.get_uuid()) {
+                return true;
+            }
+
+            homebridge.unregister_platform_accessories(
+                PLUGIN_IDENTIFIER,
+                PLATFORM_NAME,
+                PlatformAccessories::of(cached_accessory),
+            );
+            false
+        });
+
+        for (name, accessory) in accessories {
+            logger::info(&format!("Register switch: {}", name));
+
+            homebridge.register_platform_accessories(
+                PLUGIN_IDENTIFIER,
+                PLATFORM_NAME,
+                PlatformAccessories::of(accessory),
+            );
+        }
     }
 
     /// Remove cached accessories.
-    fn remove_cached(homebridge: &Homebridge, cached: Rc<RefCell<Vec<Accessory>>>) {
+    fn remove_cached(homebridge: &Homebridge, cached: &RefCell<Vec<Accessory>>) {
         for cached_accessory in cached.borrow().iter() {
             let accessories = PlatformAccessories::of(cached_accessory);
 
@@ -247,7 +549,77 @@ impl SpotifyPlatform {
 
     #[wasm_bindgen(js_name = configureAccessory)]
     /// Called by HomeBridge to restore cached accessories.
+    /// Accessories of remembered devices are restored right away, showing the remembered
+    /// volume until Spotify reports the device.
     pub fn configure_accessory(&mut self, accessory: Accessory) {
-        self.cached_devices.borrow_mut().push(accessory);
+        let uuid = accessory.get_uuid();
+        let device = self
+            .store
+            .devices()
+            .into_iter()
+            .find(|(device_id, record)| {
+                device_uuids(&self.config, device_id, &record.name).contains(&uuid)
+            });
+
+        let (device_id, record) = match device {
+            Some(device) => device,
+            None => {
+                self.cached_devices.borrow_mut().push(accessory);
+                return;
+            }
+        };
+
+        let device = create_device(
+            &self.config,
+            &device_id,
+            &record.name,
+            AccessoryContext {
+                api: self.api.clone(),
+                state: self.state.clone(),
+                scheduler: self.scheduler.clone(),
+                store: self.store.clone(),
+            },
+            Some(accessory),
+        );
+        logger::info(&format!("Restore Spotify device: {}", device.get_name()));
+
+        device.update_characteristics();
+        self.devices.borrow_mut().push(device);
     }
 }
+
+/// Create the accessory of a device, reusing the accessory restored from the cache if provided.
+fn create_device(
+    config: &Config,
+    device_id: &str,
+    device_name: &str,
+    context: AccessoryContext,
+    cached: Option<Accessory>,
+) -> SpotifyAccessory {
+    let service_type = config.service_type.clone().unwrap_or(
+        // use light as the default service type since it is supported
+        // by most platforms.
+        ServiceType::Light,
+    );
+
+    SpotifyAccessory::new(
+        device_name.to_owned(),
+        device_id.to_owned(),
+        service_type,
+        config.device_config(device_id, device_name),
+        context,
+        cached,
+    )
+}
+
+/// Return the UUIDs the accessory of a device might have: the one generated from the device ID,
+/// and the ones earlier versions generated from the configured or the Spotify device name.
+fn device_uuids(config: &Config, device_id: &str, device_name: &str) -> Vec<String> {
+    let alias = config.device_config(device_id, device_name).name;
+
+    std::iter::once(device_id)
+        .chain(alias.as_deref())
+        .chain(std::iter::once(device_name))
+        .map(UUIDGen::generate)
+        .collect()
+}
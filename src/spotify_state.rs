@@ -0,0 +1,62 @@
+//! Snapshot of the Spotify state shared between the platform and its accessories.
+
+use crate::poll_scheduler::Activity;
+use crate::spotify_api::{SpotifyDevice, SpotifyPlayback};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// State snapshot shared by the platform and all accessories.
+pub type SharedState = Rc<RefCell<SpotifyState>>;
+
+#[derive(Debug, Default)]
+/// Represents the Spotify state retrieved during the last refresh.
+pub struct SpotifyState {
+    /// Available Spotify devices
+    pub devices: Vec<SpotifyDevice>,
+    /// Current playback, `None` if nothing is being played
+    pub playback: Option<SpotifyPlayback>,
+    /// Error of the last refresh, `None` if it succeeded
+    pub error: Option<String>,
+}
+
+impl SpotifyState {
+    /// Return whether the device is currently playing.
+    pub fn is_playing(&self, device_id: &str) -> bool {
+        match &self.playback {
+            Some(playback) => playback.is_playing && playback.device.id == device_id,
+            None => false,
+        }
+    }
+
+    /// Check that the state of the device is known, i.e. the last refresh succeeded
+    /// and the device was available.
+    pub fn check_device(&self, device_id: &str) -> Result<(), String> {
+        if let Some(error) = &self.error {
+            return Err(format!("Spotify is not reachable: {}", error));
+        }
+
+        if !self.devices.iter().any(|d| d.id == device_id) {
+            return Err(format!("Spotify device {} is not available", device_id));
+        }
+
+        Ok(())
+    }
+
+    /// Return the volume of the device, if known.
+    pub fn volume(&self, device_id: &str) -> Option<u32> {
+        self.devices
+            .iter()
+            .find(|d| d.id == device_id)
+            .and_then(|d| d.volume_percent)
+    }
+
+    /// Return how actively Spotify is currently used.
+    pub fn activity(&self) -> Activity {
+        match &self.playback {
+            Some(playback) if playback.is_playing => Activity::Playing,
+            Some(_) => Activity::Available,
+            None if self.devices.iter().any(|d| d.is_active) => Activity::Available,
+            None => Activity::Idle,
+        }
+    }
+}
@@ -0,0 +1,268 @@
+//! Device state persisted in the Homebridge storage across restarts.
+
+use js_sys::Date;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::Rc;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::spawn_local;
+
+use crate::ducking;
+use crate::logger;
+use crate::node_fetch::error_message;
+use crate::snapshot::PlaybackSnapshot;
+use crate::spotify_state::SpotifyState;
+use crate::timer::Timeout;
+
+/// Name of the file in the Homebridge storage directory.
+const STATE_FILE: &str = "homebridge-rusty-spotify.json";
+/// Time changes are collected before they get written.
+const FLUSH_DELAY: u32 = 60 * 1000; // milliseconds
+
+#[wasm_bindgen]
+extern "C" {
+    type Fs;
+
+    #[wasm_bindgen(js_name = require)]
+    fn require_fs(name: &str) -> Fs;
+
+    #[wasm_bindgen(method, catch, js_name = readFileSync)]
+    fn read_file(this: &Fs, path: &str, encoding: &str) -> Result<String, JsValue>;
+
+    #[wasm_bindgen(method, catch, js_name = writeFileSync)]
+    fn write_file(this: &Fs, path: &str, data: &str) -> Result<(), JsValue>;
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+/// Represents what is remembered about a device.
+pub struct DeviceRecord {
+    /// Spotify device name
+    pub name: String,
+    /// Last known volume
+    pub volume: Option<u32>,
+    /// Album, artist or playlist last played on the device
+    pub context_uri: Option<String>,
+    /// Time the device was last available, in milliseconds since the epoch
+    pub last_seen: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+/// Represents the content of the state file.
+struct StoredState {
+    /// Remembered devices keyed by Spotify device ID
+    devices: HashMap<String, DeviceRecord>,
+    /// Device that played last
+    last_active_device: Option<String>,
+    /// Spotify URIs of presets configured by name, keyed by what was looked up, e.g. `playlist:Dinner`
+    #[serde(default)]
+    presets: HashMap<String, String>,
+    /// Playback state to restore if Homebridge stopped while ducked
+    #[serde(default)]
+    ducking: Option<ducking::Snapshot>,
+    /// Playback snapshots keyed by name
+    #[serde(default)]
+    snapshots: HashMap<String, PlaybackSnapshot>,
+}
+
+#[derive(Debug)]
+/// Keeps the device state in memory and writes it to the Homebridge storage.
+pub struct StateStore {
+    /// Path of the state file, `None` keeps the state in memory only
+    path: Option<String>,
+    /// Remembered state
+    state: RefCell<StoredState>,
+    /// Timeout writing pending changes
+    flush: RefCell<Option<Timeout>>,
+    /// Whether the state changed since it was last written
+    unsaved: Cell<bool>,
+}
+
+impl StateStore {
+    /// Load the state stored in the provided directory.
+    pub fn load(storage_path: Option<String>) -> StateStore {
+        let path = storage_path.map(|dir| format!("{}/{}", dir, STATE_FILE));
+
+        // a missing or broken file only means that nothing is remembered yet
+        let state = path
+            .as_ref()
+            .and_then(|path| require_fs("fs").read_file(path, "utf8").ok())
+            .and_then(|data| match serde_json::from_str(&data) {
+                Ok(state) => Some(state),
+                Err(e) => {
+                    logger::warn(&format!("Ignoring invalid state file: {}", e));
+                    None
+                }
+            })
+            .unwrap_or_default();
+
+        StateStore {
+            path,
+            state: RefCell::new(state),
+            flush: RefCell::new(None),
+            unsaved: Cell::new(false),
+        }
+    }
+
+    /// Return the IDs and records of all remembered devices.
+    pub fn devices(&self) -> Vec<(String, DeviceRecord)> {
+        self.state
+            .borrow()
+            .devices
+            .iter()
+            .map(|(id, record)| (id.clone(), record.clone()))
+            .collect()
+    }
+
+    /// Return what is remembered about a device.
+    pub fn device(&self, device_id: &str) -> Option<DeviceRecord> {
+        self.state.borrow().devices.get(device_id).cloned()
+    }
+
+    /// Return the album, artist or playlist to resume on a device: the one last played
+    /// on the device, otherwise the one last played on any device.
+    pub fn context_uri(&self, device_id: &str) -> Option<String> {
+        let state = self.state.borrow();
+        let context_uri = |id: &str| state.devices.get(id).and_then(|d| d.context_uri.clone());
+
+        context_uri(device_id).or_else(|| state.last_active_device.as_deref().and_then(context_uri))
+    }
+
+    /// Remember the state retrieved during a refresh. Changes of the volume, context or active
+    /// device are written to storage shortly after, `last_seen` only gets written along with them.
+    pub fn record(self: &Rc<Self>, spotify_state: &SpotifyState) {
+        let mut changed = false;
+        {
+            let mut state = self.state.borrow_mut();
+            let now = Date::now();
+
+            for device in &spotify_state.devices {
+                let record = state.devices.entry(device.id.clone()).or_default();
+                let volume = device.volume_percent.or(record.volume);
+
+                changed |= record.name != device.name || record.volume != volume;
+                record.name = device.name.clone();
+                record.volume = volume;
+                record.last_seen = now;
+            }
+
+            if let Some(playback) = &spotify_state.playback {
+                let record = state.devices.entry(playback.device.id.clone()).or_default();
+                if let Some(context) = &playback.context {
+                    changed |= record.context_uri.as_deref() != Some(context.uri.as_str());
+                    record.context_uri = Some(context.uri.clone());
+                }
+
+                changed |= state.last_active_device.as_deref() != Some(playback.device.id.as_str());
+                state.last_active_device = Some(playback.device.id.clone());
+            }
+        }
+
+        self.unsaved.set(true);
+        if changed {
+            self.schedule_flush();
+        }
+    }
+
+    /// Return the URI a preset was resolved to before.
+    pub fn preset_uri(&self, key: &str) -> Option<String> {
+        self.state.borrow().presets.get(key).cloned()
+    }
+
+    /// Remember the URI a preset was resolved to, written to storage shortly after.
+    pub fn record_preset_uri(self: &Rc<Self>, key: &str, uri: &str) {
+        let previous = self
+            .state
+            .borrow_mut()
+            .presets
+            .insert(key.to_owned(), uri.to_owned());
+
+        if previous.as_deref() != Some(uri) {
+            self.unsaved.set(true);
+            self.schedule_flush();
+        }
+    }
+
+    /// Return the playback state that is still to be restored after ducking.
+    pub fn ducking(&self) -> Option<ducking::Snapshot> {
+        self.state.borrow().ducking.clone()
+    }
+
+    /// Remember the playback state to restore after ducking, written to storage right away
+    /// since Homebridge might stop before it gets restored.
+    pub fn record_ducking(&self, snapshot: Option<ducking::Snapshot>) {
+        self.state.borrow_mut().ducking = snapshot;
+        self.unsaved.set(true);
+        self.flush();
+    }
+
+    /// Return the snapshot captured under the provided name.
+    pub fn snapshot(&self, name: &str) -> Option<PlaybackSnapshot> {
+        self.state.borrow().snapshots.get(name).cloned()
+    }
+
+    /// Remember a snapshot, written to storage right away so that it survives a restart.
+    pub fn record_snapshot(&self, name: &str, snapshot: PlaybackSnapshot) {
+        self.state
+            .borrow_mut()
+            .snapshots
+            .insert(name.to_owned(), snapshot);
+        self.unsaved.set(true);
+        self.flush();
+    }
+
+    /// Forget a snapshot, returns whether it existed.
+    pub fn remove_snapshot(&self, name: &str) -> bool {
+        let removed = self.state.borrow_mut().snapshots.remove(name).is_some();
+
+        if removed {
+            self.unsaved.set(true);
+            self.flush();
+        }
+        removed
+    }
+
+    /// Write pending changes once the flush delay passed.
+    fn schedule_flush(self: &Rc<Self>) {
+        if self.flush.borrow().is_none() {
+            let store = Rc::clone(self);
+            let timeout = Timeout::new(FLUSH_DELAY, move || {
+                // the timeout can only be released once its callback returned
+                spawn_local(async move { store.flush() });
+            });
+            self.flush.replace(Some(timeout));
+        }
+    }
+
+    /// Write pending changes right away, e.g. when Homebridge shuts down.
+    pub fn flush(&self) {
+        self.flush.replace(None);
+
+        if self.unsaved.replace(false) {
+            self.write();
+        }
+    }
+
+    fn write(&self) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+
+        let data = match serde_json::to_string_pretty(&*self.state.borrow()) {
+            Ok(data) => data,
+            Err(e) => {
+                logger::error(&format!("Failed to serialize device state: {}", e));
+                return;
+            }
+        };
+
+        match require_fs("fs").write_file(path, &data) {
+            Ok(_) => logger::debug(&format!("Device state written to {}", path)),
+            Err(e) => logger::error(&format!(
+                "Failed to write device state to {}: {}",
+                path,
+                error_message(&e)
+            )),
+        }
+    }
+}
@@ -0,0 +1,52 @@
+//! Timers based on the JavaScript setTimeout.
+
+use js_sys::{Function, Promise};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::JsFuture;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(js_name = setTimeout)]
+    pub fn set_timeout(closure: &Function, millis: u32) -> JsValue;
+
+    #[wasm_bindgen(js_name = clearTimeout)]
+    pub fn clear_timeout(handle: &JsValue);
+}
+
+#[derive(Debug)]
+/// Represents a pending timeout which gets cancelled when dropped.
+pub struct Timeout {
+    /// Handle returned by setTimeout
+    handle: JsValue,
+    /// Callback executed when the timeout fires
+    _callback: Closure<dyn FnMut()>,
+}
+
+impl Timeout {
+    /// Execute the callback after the provided number of milliseconds.
+    pub fn new<F: FnOnce() + 'static>(millis: u32, callback: F) -> Timeout {
+        let callback = Closure::once(callback);
+        let handle = set_timeout(callback.as_ref().unchecked_ref(), millis);
+
+        Timeout {
+            handle,
+            _callback: callback,
+        }
+    }
+}
+
+impl Drop for Timeout {
+    fn drop(&mut self) {
+        clear_timeout(&self.handle);
+    }
+}
+
+/// Wait for the provided number of milliseconds.
+pub async fn sleep(millis: u32) {
+    let promise = Promise::new(&mut |resolve, _| {
+        set_timeout(&resolve, millis);
+    });
+
+    let _ = JsFuture::from(promise).await;
+}
@@ -0,0 +1,223 @@
+//! Mapping between HomeKit slider values and Spotify volumes.
+
+use std::cell::Cell;
+
+use crate::config::DeviceConfig;
+
+/// Steepness of the logarithmic curve, higher values give finer control at low volumes.
+const LOGARITHMIC_STEEPNESS: f64 = 3.0;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+/// Curve used to map HomeKit slider values to Spotify volumes.
+pub enum VolumeCurve {
+    /// `"linear"` or `"logarithmic"`
+    Named(NamedVolumeCurve),
+    /// `[homekit, spotify]` pairs, values between them are interpolated linearly
+    Breakpoints(Vec<(u32, u32)>),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+/// Predefined volume curves.
+pub enum NamedVolumeCurve {
+    #[serde(rename = "linear")]
+    Linear,
+    #[serde(rename = "logarithmic")]
+    Logarithmic,
+}
+
+impl VolumeCurve {
+    /// Map a HomeKit slider value (0-100) to a Spotify volume (0-100).
+    fn apply(&self, value: f64) -> f64 {
+        match self {
+            VolumeCurve::Named(NamedVolumeCurve::Linear) => value,
+            VolumeCurve::Named(NamedVolumeCurve::Logarithmic) => {
+                100.0 * ((LOGARITHMIC_STEEPNESS * value / 100.0).exp() - 1.0)
+                    / (LOGARITHMIC_STEEPNESS.exp() - 1.0)
+            }
+            VolumeCurve::Breakpoints(breakpoints) => interpolate(breakpoints, value),
+        }
+    }
+}
+
+/// Linearly interpolate between breakpoints sorted by their HomeKit value.
+fn interpolate(breakpoints: &[(u32, u32)], value: f64) -> f64 {
+    let (first, last) = match (breakpoints.first(), breakpoints.last()) {
+        (Some(first), Some(last)) => (first, last),
+        _ => return value,
+    };
+
+    if value <= f64::from(first.0) {
+        return f64::from(first.1);
+    }
+
+    for window in breakpoints.windows(2) {
+        let (x0, y0) = (f64::from(window[0].0), f64::from(window[0].1));
+        let (x1, y1) = (f64::from(window[1].0), f64::from(window[1].1));
+
+        if value <= x1 {
+            return y0 + (y1 - y0) * (value - x0) / (x1 - x0);
+        }
+    }
+
+    f64::from(last.1)
+}
+
+#[derive(Debug)]
+/// Converts volumes between HomeKit and Spotify in both directions.
+pub struct VolumeMapping {
+    /// Curve applied to HomeKit slider values
+    curve: VolumeCurve,
+    /// Spotify volume at the top of the slider
+    cap: u32,
+    /// Lowest Spotify volume that can be set
+    min_volume: u32,
+    /// Highest Spotify volume that can be set
+    max_volume: u32,
+    /// Last HomeKit value mapped to Spotify and the resulting Spotify volume
+    last_mapped: Cell<Option<(u32, u32)>>,
+}
+
+impl VolumeMapping {
+    pub fn new(device_config: &DeviceConfig) -> VolumeMapping {
+        VolumeMapping {
+            curve: device_config
+                .volume_curve
+                .clone()
+                .unwrap_or(VolumeCurve::Named(NamedVolumeCurve::Linear)),
+            cap: device_config.volume_cap.unwrap_or(100),
+            min_volume: device_config.min_volume.unwrap_or(0),
+            max_volume: device_config.max_volume.unwrap_or(100),
+            last_mapped: Cell::new(None),
+        }
+    }
+
+    /// Convert a HomeKit slider value to the Spotify volume.
+    pub fn to_spotify(&self, homekit_volume: u32) -> u32 {
+        let volume = self.map(homekit_volume);
+        self.last_mapped.set(Some((homekit_volume, volume)));
+        volume
+    }
+
+    /// Convert a Spotify volume to the HomeKit slider value.
+    pub fn to_homekit(&self, spotify_volume: u32) -> u32 {
+        // several slider values can map to the same volume,
+        // keep the one that was set from HomeKit so that the slider doesn't move
+        if let Some((homekit_volume, volume)) = self.last_mapped.get() {
+            if volume == spotify_volume {
+                return homekit_volume;
+            }
+        }
+
+        // the mapping is monotonic, pick the closest slider value
+        (0..=100)
+            .min_by_key(|&homekit_volume| {
+                (i64::from(self.map(homekit_volume)) - i64::from(spotify_volume)).abs()
+            })
+            .unwrap_or(spotify_volume)
+    }
+
+    /// Keep a Spotify volume within the cap and volume limits, e.g. a configured default volume.
+    pub fn limit(&self, spotify_volume: u32) -> u32 {
+        spotify_volume
+            .min(self.cap)
+            .max(self.min_volume)
+            .min(self.max_volume)
+    }
+
+    /// Apply curve, cap and volume limits.
+    fn map(&self, homekit_volume: u32) -> u32 {
+        let volume = self.curve.apply(f64::from(homekit_volume.min(100)));
+        let volume = (volume * f64::from(self.cap) / 100.0).round().max(0.0) as u32;
+
+        volume.max(self.min_volume).min(self.max_volume)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapping(device_config: DeviceConfig) -> VolumeMapping {
+        VolumeMapping::new(&device_config)
+    }
+
+    #[test]
+    fn interpolate_between_breakpoints() {
+        let breakpoints = [(0, 0), (50, 20), (100, 100)];
+
+        assert_eq!(interpolate(&breakpoints, 0.0), 0.0);
+        assert_eq!(interpolate(&breakpoints, 25.0), 10.0);
+        assert_eq!(interpolate(&breakpoints, 50.0), 20.0);
+        assert_eq!(interpolate(&breakpoints, 75.0), 60.0);
+        assert_eq!(interpolate(&breakpoints, 100.0), 100.0);
+    }
+
+    #[test]
+    fn interpolate_outside_breakpoints() {
+        let breakpoints = [(10, 5), (90, 80)];
+
+        assert_eq!(interpolate(&breakpoints, 0.0), 5.0);
+        assert_eq!(interpolate(&breakpoints, 100.0), 80.0);
+        assert_eq!(interpolate(&[], 42.0), 42.0);
+    }
+
+    #[test]
+    fn linear_round_trip() {
+        let mapping = mapping(DeviceConfig::default());
+
+        for volume in 0..=100 {
+            assert_eq!(mapping.to_spotify(volume), volume);
+            assert_eq!(mapping.to_homekit(volume), volume);
+        }
+    }
+
+    #[test]
+    fn logarithmic_round_trip() {
+        let mapping = mapping(DeviceConfig {
+            volume_curve: Some(VolumeCurve::Named(NamedVolumeCurve::Logarithmic)),
+            ..DeviceConfig::default()
+        });
+
+        assert_eq!(mapping.to_spotify(0), 0);
+        assert_eq!(mapping.to_spotify(100), 100);
+        assert!(mapping.to_spotify(50) < 50);
+
+        for homekit_volume in 0..=100 {
+            let volume = mapping.to_spotify(homekit_volume);
+            assert_eq!(mapping.to_homekit(volume), homekit_volume);
+        }
+    }
+
+    #[test]
+    fn round_trip_keeps_slider_value() {
+        // slider values 0-4 all map to the lowest volume
+        let mapping = mapping(DeviceConfig {
+            min_volume: Some(5),
+            ..DeviceConfig::default()
+        });
+
+        assert_eq!(mapping.to_spotify(3), 5);
+        assert_eq!(mapping.to_homekit(5), 3);
+        assert_eq!(mapping.to_homekit(60), 60);
+    }
+
+    #[test]
+    fn cap_and_limits() {
+        let mapping = mapping(DeviceConfig {
+            volume_cap: Some(60),
+            min_volume: Some(10),
+            max_volume: Some(50),
+            ..DeviceConfig::default()
+        });
+
+        assert_eq!(mapping.to_spotify(0), 10);
+        assert_eq!(mapping.to_spotify(50), 30);
+        assert_eq!(mapping.to_spotify(100), 50);
+        assert_eq!(mapping.to_homekit(30), 50);
+
+        assert_eq!(mapping.limit(5), 10);
+        assert_eq!(mapping.limit(40), 40);
+        assert_eq!(mapping.limit(80), 50);
+    }
+}
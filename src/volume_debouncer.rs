@@ -0,0 +1,143 @@
+//! Coalesces volume changes from HomeKit into a single request.
+
+use js_sys::{Date, Function};
+use std::cell::RefCell;
+use std::rc::Rc;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::{spawn_local, JsFuture};
+
+use crate::node_fetch::error_message;
+use crate::spotify_accessory::respond;
+use crate::spotify_api::SpotifyApi;
+use crate::timer::Timeout;
+
+/// Time without volume changes after which the latest volume is sent to Spotify.
+const DEBOUNCE_DELAY: u32 = 500; // milliseconds
+/// Time after which a sent volume is no longer reported if Spotify didn't confirm it.
+const CONFIRMATION_TIMEOUT: f64 = 10.0 * 1000.0; // milliseconds
+
+#[derive(Debug, Default)]
+/// Represents volume changes that haven't been confirmed by Spotify yet.
+struct PendingVolume {
+    /// Latest volume requested from HomeKit that hasn't been sent yet
+    requested: Option<u32>,
+    /// HomeKit callbacks waiting for the requested volume to be sent
+    callbacks: Vec<Function>,
+    /// Timeout sending the requested volume
+    timeout: Option<Timeout>,
+    /// Volume sent to Spotify and the time it was sent at
+    sent: Option<(u32, f64)>,
+}
+
+#[derive(Debug)]
+/// Sends only the latest volume after the slider has been released.
+pub struct VolumeDebouncer {
+    /// API to control Spotify
+    api: Rc<SpotifyApi>,
+    /// ID of device to be controlled
+    device_id: String,
+    /// Volume changes in progress
+    pending: Rc<RefCell<PendingVolume>>,
+}
+
+impl VolumeDebouncer {
+    pub fn new(api: Rc<SpotifyApi>, device_id: String) -> VolumeDebouncer {
+        VolumeDebouncer {
+            api,
+            device_id,
+            pending: Rc::new(RefCell::new(PendingVolume::default())),
+        }
+    }
+
+    /// Request a volume change. The callback gets invoked once the
+    /// latest requested volume has been sent to Spotify.
+    pub fn set_volume(&self, volume: u32, callback: Function) {
+        let api = self.api.clone();
+        let device_id = self.device_id.clone();
+        let pending = self.pending.clone();
+
+        let timeout = Timeout::new(DEBOUNCE_DELAY, move || {
+            spawn_local(Self::send(api, device_id, pending));
+        });
+
+        let mut pending = self.pending.borrow_mut();
+        pending.requested = Some(volume);
+        pending.callbacks.push(callback);
+        // replacing the timeout cancels the previous one
+        pending.timeout = Some(timeout);
+    }
+
+    /// Drop a volume change that hasn't been sent yet, e.g. when Homebridge shuts down.
+    /// The waiting callbacks get an error so that HomeKit doesn't wait for them.
+    pub fn stop(&self) {
+        let callbacks = {
+            let mut pending = self.pending.borrow_mut();
+            pending.timeout = None;
+            pending.requested = None;
+            std::mem::take(&mut pending.callbacks)
+        };
+
+        for callback in callbacks {
+            respond(
+                &callback,
+                Err(format!(
+                    "Volume of Spotify device {} was not changed before shutting down",
+                    self.device_id
+                )),
+            );
+        }
+    }
+
+    /// Return the volume requested from HomeKit that Spotify hasn't reported yet.
+    pub fn optimistic_volume(&self) -> Option<u32> {
+        let pending = self.pending.borrow();
+        pending.requested.or(pending.sent.map(|(volume, _)| volume))
+    }
+
+    /// Forget the sent volume once Spotify reports it, or if it didn't get applied.
+    pub fn confirm(&self, reported_volume: Option<u32>) {
+        let mut pending = self.pending.borrow_mut();
+
+        if let Some((volume, sent_at)) = pending.sent {
+            if reported_volume == Some(volume) || Date::now() - sent_at > CONFIRMATION_TIMEOUT {
+                pending.sent = None;
+            }
+        }
+    }
+
+    /// Send the latest requested volume and notify the waiting callbacks.
+    async fn send(api: Rc<SpotifyApi>, device_id: String, pending: Rc<RefCell<PendingVolume>>) {
+        let (volume, callbacks) = {
+            let mut pending = pending.borrow_mut();
+            pending.timeout = None;
+
+            match pending.requested.take() {
+                Some(volume) => {
+                    pending.sent = Some((volume, Date::now()));
+                    (volume, std::mem::take(&mut pending.callbacks))
+                }
+                None => return,
+            }
+        };
+
+        let result = JsFuture::from(api.set_volume(device_id, volume)).await;
+
+        if result.is_err() {
+            // don't report a volume that didn't get applied
+            let mut pending = pending.borrow_mut();
+            if pending.sent.map(|(v, _)| v) == Some(volume) {
+                pending.sent = None;
+            }
+        }
+
+        for callback in callbacks {
+            respond(
+                &callback,
+                result
+                    .as_ref()
+                    .map(|_| JsValue::from(volume))
+                    .map_err(error_message),
+            );
+        }
+    }
+}
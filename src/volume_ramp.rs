@@ -0,0 +1,70 @@
+//! Gradual volume changes, e.g. for fading in and out.
+
+use std::cell::Cell;
+use std::rc::Rc;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::JsFuture;
+
+use crate::spotify_api::SpotifyApi;
+use crate::timer::sleep;
+
+/// Minimum time between two volume requests to stay within the Spotify rate limit.
+const MIN_STEP_INTERVAL: u32 = 1000; // milliseconds
+/// Maximum number of volume requests per ramp.
+const MAX_STEPS: u32 = 20;
+
+#[derive(Debug, Default)]
+/// Changes the volume of a device in steps. Only one ramp runs per device,
+/// starting a new ramp cancels the running one.
+pub struct VolumeRamp {
+    /// Incremented whenever a ramp gets started or cancelled
+    generation: Rc<Cell<u32>>,
+}
+
+impl VolumeRamp {
+    pub fn new() -> VolumeRamp {
+        VolumeRamp::default()
+    }
+
+    /// Cancel the running ramp.
+    pub fn cancel(&self) {
+        self.generation.set(self.generation.get().wrapping_add(1));
+    }
+
+    /// Change the volume from `from` to `to` over `duration` milliseconds.
+    /// Resolves to `false` if the ramp got cancelled before reaching the target volume.
+    pub async fn run(
+        &self,
+        api: &SpotifyApi,
+        device_id: &str,
+        from: u32,
+        to: u32,
+        duration: u32,
+    ) -> Result<bool, JsValue> {
+        self.cancel();
+        let generation = self.generation.get();
+
+        let distance = (i64::from(to) - i64::from(from)).unsigned_abs() as u32;
+        let steps = (duration / MIN_STEP_INTERVAL)
+            .min(MAX_STEPS)
+            .min(distance)
+            .max(1);
+        let interval = duration / steps;
+
+        JsFuture::from(api.set_volume(device_id.to_owned(), from)).await?;
+
+        for step in 1..=steps {
+            sleep(interval).await;
+
+            if self.generation.get() != generation {
+                return Ok(false);
+            }
+
+            let volume = i64::from(from)
+                + (i64::from(to) - i64::from(from)) * i64::from(step) / i64::from(steps);
+            JsFuture::from(api.set_volume(device_id.to_owned(), volume as u32)).await?;
+        }
+
+        Ok(self.generation.get() == generation)
+    }
+}